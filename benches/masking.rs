@@ -0,0 +1,24 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use yaws::mask::{apply_mask, apply_mask_scalar, apply_mask_words};
+
+fn masking(c: &mut Criterion) {
+    let mut group = c.benchmark_group("masking");
+    for &size in &[64usize, 4 << 10, 64 << 10, 1 << 20] {
+        //offset by one so the aligned paths have to deal with a ragged head
+        let mut data = vec![0x5Au8; size + 1];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("scalar", size), &size, |b, _| {
+            b.iter(|| apply_mask_scalar(&mut data[1..], 0x1234_5678))
+        });
+        group.bench_with_input(BenchmarkId::new("words", size), &size, |b, _| {
+            b.iter(|| apply_mask_words(&mut data[1..], 0x1234_5678))
+        });
+        group.bench_with_input(BenchmarkId::new("auto", size), &size, |b, _| {
+            b.iter(|| apply_mask(&mut data[1..], 0x1234_5678))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, masking);
+criterion_main!(benches);
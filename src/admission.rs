@@ -0,0 +1,395 @@
+use http::StatusCode;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// caps on what a server lets in. None is unlimited, which is the default for all the caps
+// on upgrades.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    //connections being told they're over the limits at once, past this they're just closed.
+    //128 by default, refusals are cheap but shouldn't pile up either
+    pub max_refusing: usize,
+    //open connections in total, past this new ones get a 503
+    pub max_connections: Option<usize>,
+    //open connections from a single ip, past this new ones get a 429
+    pub max_connections_per_ip: Option<usize>,
+    //new connections a single ip may open per second, with bursts of up to as many.
+    //past this they get a 429
+    pub upgrades_per_second_per_ip: Option<u32>,
+    //what Retry-After says for the connection caps, rate limited clients are told
+    //when their next upgrade will be let in
+    pub retry_after: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_refusing: 128,
+            max_connections: None,
+            max_connections_per_ip: None,
+            upgrades_per_second_per_ip: None,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Limits {
+    pub fn with_max_refusing(mut self, max: usize) -> Self {
+        self.max_refusing = max;
+        self
+    }
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max);
+        self
+    }
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> Self {
+        self.max_connections_per_ip = Some(max);
+        self
+    }
+    pub fn with_upgrades_per_second_per_ip(mut self, rate: u32) -> Self {
+        self.upgrades_per_second_per_ip = Some(rate);
+        self
+    }
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+}
+
+// how many connections are open and how many were turned away, and why.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdmissionStats {
+    pub open: usize,
+    //ips with at least one open connection
+    pub open_ips: usize,
+    //connections being sent a 503 or 429 right now
+    pub refusing: usize,
+    //503s for max_connections
+    pub rejected_busy: u64,
+    //429s for max_connections_per_ip
+    pub rejected_per_ip: u64,
+    //429s for upgrades_per_second_per_ip
+    pub rejected_rate: u64,
+}
+
+#[derive(Debug)]
+struct PerIp {
+    open: usize,
+    //token bucket for the upgrade rate, refilled on every new connection
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl PerIp {
+    fn refill(&mut self, rate: Option<u32>, now: Instant) {
+        if let Some(rate) = rate {
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.refilled = now;
+    }
+    // whether the entry can go without forgetting anything about the ip.
+    fn is_idle(&self, rate: Option<u32>) -> bool {
+        self.open == 0 && rate.is_none_or(|rate| self.tokens >= rate as f64)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    limits: Limits,
+    open: usize,
+    by_ip: HashMap<IpAddr, PerIp>,
+    //how big by_ip may get before idle entries are cleaned out
+    prune_at: usize,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: Mutex<State>,
+    rejected_busy: AtomicU64,
+    rejected_per_ip: AtomicU64,
+    rejected_rate: AtomicU64,
+    refusing: AtomicUsize,
+}
+
+// the limits of a Server and what they've done so far. Cloned handles share both,
+// so limits can be changed while the server runs.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    inner: Arc<Inner>,
+}
+
+// held for as long as an admitted connection is open.
+#[derive(Debug)]
+pub(crate) struct Admitted {
+    inner: Arc<Inner>,
+    ip: IpAddr,
+}
+
+impl Drop for Admitted {
+    fn drop(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.open -= 1;
+        let rate = state.limits.upgrades_per_second_per_ip;
+        if let Some(per_ip) = state.by_ip.get_mut(&self.ip) {
+            per_ip.open -= 1;
+            per_ip.refill(rate, Instant::now());
+            if per_ip.is_idle(rate) {
+                state.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// held while a refused connection gets its response.
+#[derive(Debug)]
+pub(crate) struct Refusing {
+    inner: Arc<Inner>,
+}
+
+impl Drop for Refusing {
+    fn drop(&mut self) {
+        self.inner.refusing.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// why a connection was turned away, and when it's worth trying again.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Refusal {
+    pub(crate) status: StatusCode,
+    pub(crate) reason: &'static str,
+    pub(crate) retry_after: Duration,
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Admission::new(Limits::default())
+    }
+}
+
+impl Admission {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Admission {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    limits,
+                    open: 0,
+                    by_ip: HashMap::new(),
+                    prune_at: 1024,
+                }),
+                rejected_busy: AtomicU64::new(0),
+                rejected_per_ip: AtomicU64::new(0),
+                rejected_rate: AtomicU64::new(0),
+                refusing: AtomicUsize::new(0),
+            }),
+        }
+    }
+    pub fn limits(&self) -> Limits {
+        self.inner.state.lock().unwrap().limits
+    }
+    // applies to new connections, ones that are already open are left alone.
+    pub fn set_limits(&self, limits: Limits) {
+        self.inner.state.lock().unwrap().limits = limits;
+    }
+    pub fn stats(&self) -> AdmissionStats {
+        let state = self.inner.state.lock().unwrap();
+        AdmissionStats {
+            open: state.open,
+            open_ips: state
+                .by_ip
+                .values()
+                .filter(|per_ip| per_ip.open > 0)
+                .count(),
+            rejected_busy: self.inner.rejected_busy.load(Ordering::Relaxed),
+            rejected_per_ip: self.inner.rejected_per_ip.load(Ordering::Relaxed),
+            rejected_rate: self.inner.rejected_rate.load(Ordering::Relaxed),
+            refusing: self.inner.refusing.load(Ordering::SeqCst),
+        }
+    }
+    pub fn open_from(&self, ip: IpAddr) -> usize {
+        let state = self.inner.state.lock().unwrap();
+        state.by_ip.get(&ip).map_or(0, |per_ip| per_ip.open)
+    }
+    // a slot for telling a refused connection so, None when too many are being told already.
+    pub(crate) fn refusing(&self) -> Option<Refusing> {
+        let max = self.limits().max_refusing;
+        self.inner
+            .refusing
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |refusing| {
+                (refusing < max).then(|| refusing + 1)
+            })
+            .ok()?;
+        Some(Refusing {
+            inner: self.inner.clone(),
+        })
+    }
+    // counts a new connection from `ip` against the limits.
+    pub(crate) fn admit(&self, ip: IpAddr) -> Result<Admitted, Refusal> {
+        self.admit_at(ip, Instant::now())
+    }
+    // admit, as of `now`.
+    fn admit_at(&self, ip: IpAddr, now: Instant) -> Result<Admitted, Refusal> {
+        let mut state = self.inner.state.lock().unwrap();
+        let limits = state.limits;
+        let rate = limits.upgrades_per_second_per_ip;
+        if state.by_ip.len() >= state.prune_at {
+            state.by_ip.retain(|_, per_ip| {
+                per_ip.refill(rate, now);
+                !per_ip.is_idle(rate)
+            });
+            state.prune_at = (state.by_ip.len() * 2).max(1024);
+        }
+        if limits.max_connections.is_some_and(|max| state.open >= max) {
+            self.inner.rejected_busy.fetch_add(1, Ordering::Relaxed);
+            return Err(Refusal {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                reason: "too many connections",
+                retry_after: limits.retry_after,
+            });
+        }
+        let per_ip = state.by_ip.entry(ip).or_insert_with(|| PerIp {
+            open: 0,
+            tokens: rate.unwrap_or(0) as f64,
+            refilled: now,
+        });
+        per_ip.refill(rate, now);
+        if limits
+            .max_connections_per_ip
+            .is_some_and(|max| per_ip.open >= max)
+        {
+            self.inner.rejected_per_ip.fetch_add(1, Ordering::Relaxed);
+            return Err(Refusal {
+                status: StatusCode::TOO_MANY_REQUESTS,
+                reason: "too many connections from this address",
+                retry_after: limits.retry_after,
+            });
+        }
+        if let Some(rate) = rate {
+            if per_ip.tokens < 1.0 {
+                self.inner.rejected_rate.fetch_add(1, Ordering::Relaxed);
+                return Err(Refusal {
+                    status: StatusCode::TOO_MANY_REQUESTS,
+                    reason: "too many new connections from this address",
+                    retry_after: match rate {
+                        0 => limits.retry_after,
+                        rate => Duration::from_secs_f64((1.0 - per_ip.tokens) / rate as f64),
+                    },
+                });
+            }
+            per_ip.tokens -= 1.0;
+        }
+        per_ip.open += 1;
+        state.open += 1;
+        Ok(Admitted {
+            inner: self.inner.clone(),
+            ip,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::from(n.to_be_bytes())
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn refills_the_token_bucket_over_time() {
+        let admission = Admission::new(Limits::default().with_upgrades_per_second_per_ip(2));
+        let start = Instant::now();
+        let mut open = vec![];
+        //a burst of up to the rate
+        open.push(admission.admit_at(ip(1), start).unwrap());
+        open.push(admission.admit_at(ip(1), start).unwrap());
+        let refusal = admission.admit_at(ip(1), start).unwrap_err();
+        assert_eq!(refusal.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refusal.retry_after, ms(500));
+        //other addresses have their own bucket
+        open.push(admission.admit_at(ip(2), start).unwrap());
+
+        let refusal = admission.admit_at(ip(1), start + ms(250)).unwrap_err();
+        assert_eq!(refusal.retry_after, ms(250));
+        open.push(admission.admit_at(ip(1), start + ms(500)).unwrap());
+        //a long pause doesn't buy more than a burst
+        let later = start + Duration::from_secs(10);
+        open.push(admission.admit_at(ip(1), later).unwrap());
+        open.push(admission.admit_at(ip(1), later).unwrap());
+        assert!(admission.admit_at(ip(1), later).is_err());
+
+        let stats = admission.stats();
+        assert_eq!((stats.open, stats.open_ips), (6, 2));
+        assert_eq!(stats.rejected_rate, 3);
+        assert_eq!(admission.open_from(ip(1)), 5);
+    }
+
+    #[test]
+    fn caps_connections_in_total_and_per_address() {
+        let limits = Limits::default()
+            .with_max_connections(3)
+            .with_max_connections_per_ip(2)
+            .with_retry_after(Duration::from_secs(7));
+        let admission = Admission::new(limits);
+        let now = Instant::now();
+        let first = admission.admit_at(ip(1), now).unwrap();
+        let _second = admission.admit_at(ip(1), now).unwrap();
+        let refusal = admission.admit_at(ip(1), now).unwrap_err();
+        assert_eq!(refusal.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(refusal.retry_after, Duration::from_secs(7));
+        let _third = admission.admit_at(ip(2), now).unwrap();
+        let refusal = admission.admit_at(ip(3), now).unwrap_err();
+        assert_eq!(refusal.status, StatusCode::SERVICE_UNAVAILABLE);
+
+        //closing one makes room for its address again
+        drop(first);
+        let _fourth = admission.admit_at(ip(1), now).unwrap();
+        assert_eq!(
+            admission.stats(),
+            AdmissionStats {
+                open: 3,
+                open_ips: 2,
+                refusing: 0,
+                rejected_busy: 1,
+                rejected_per_ip: 1,
+                rejected_rate: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn forgets_addresses_that_are_idle_again() {
+        let admission = Admission::new(Limits::default().with_upgrades_per_second_per_ip(1));
+        let start = Instant::now();
+        //each leaves an empty bucket behind, so it's remembered after the connection closes
+        for n in 0..1024 {
+            drop(admission.admit_at(ip(n), start).unwrap());
+        }
+        assert_eq!(admission.inner.state.lock().unwrap().by_ip.len(), 1024);
+        assert_eq!(admission.stats().open_ips, 0);
+        //a second later every bucket is full again, the next connection cleans them out
+        let _open = admission.admit_at(ip(5000), start + Duration::from_secs(2));
+        let state = admission.inner.state.lock().unwrap();
+        assert_eq!(state.by_ip.len(), 1);
+        assert_eq!(state.prune_at, 1024);
+    }
+
+    #[test]
+    fn caps_how_many_are_refused_at_once() {
+        let admission = Admission::new(Limits::default().with_max_refusing(2));
+        let first = admission.refusing().unwrap();
+        let _second = admission.refusing().unwrap();
+        assert!(admission.refusing().is_none());
+        assert_eq!(admission.stats().refusing, 2);
+        drop(first);
+        assert_eq!(admission.stats().refusing, 1);
+        assert!(admission.refusing().is_some());
+    }
+}
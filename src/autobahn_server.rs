@@ -0,0 +1,59 @@
+//echo server for the autobahn testsuite's fuzzingclient mode, see the README for running it.
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use yaws::{DeflatePolicy, Message, Request, Router, Server};
+
+// the code to close with after failing to read a message, None when the connection is just gone.
+fn close_code(e: &std::io::Error) -> Option<u16> {
+    let inner = e.get_ref();
+    if let Some(e) = inner.and_then(|e| e.downcast_ref::<yaws::FrameError>()) {
+        Some(e.close_code())
+    } else if let Some(e) = inner.and_then(|e| e.downcast_ref::<yaws::InvalidUtf8>()) {
+        Some(e.close_code())
+    } else if let Some(e) = inner.and_then(|e| e.downcast_ref::<yaws::DeflateError>()) {
+        Some(e.close_code())
+    } else if let Some(e) = inner.and_then(|e| e.downcast_ref::<yaws::TooBig>()) {
+        Some(e.close_code())
+    } else if e.kind() == std::io::ErrorKind::InvalidData {
+        Some(1002)
+    } else {
+        None
+    }
+}
+
+async fn echo(mut client: yaws::Client<TcpStream>, _: Request) {
+    //the suite judges by what's on the wire, errors past that don't matter here
+    let _ = async {
+        while let Some(message) = client.next().await {
+            match message {
+                Ok(Message::Ping(data)) => client.send(Message::Pong(data)).await?,
+                Ok(Message::Pong(_)) => {}
+                Ok(Message::Close(code)) => return client.send(Message::Close(code)).await,
+                Ok(message) => client.send(message).await?,
+                Err(e) => {
+                    if let Some(code) = close_code(&e) {
+                        client.send(Message::Close(Some(code))).await?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    }
+    .await;
+}
+
+#[tokio::main]
+async fn main() -> Result<(), std::io::Error> {
+    pretty_env_logger::init();
+    let addr = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9002".to_owned());
+    //a threshold of 0 compresses everything, so the compression cases see every message deflated
+    let router = Router::new()
+        .route("/", echo)
+        .permessage_deflate(DeflatePolicy::default().with_threshold(0));
+    let server = Server::bind(&addr, router).await?;
+    println!("listening on ws://{}", server.local_addr()?);
+    server.run().await
+}
@@ -8,18 +8,36 @@ async fn do_case(case: usize) -> Result<Option<u16>, std::io::Error> {
     ))
     .await
     .unwrap();
+    let role = client.role();
     let (mut reader, mut writer) = client.split();
     let mut response_buffer = vec![];
     let mut current_continue = None;
-    while let Ok(frame) = reader.read_message().await {
+    loop {
+        let frame = match reader.read_message().await {
+            Ok(frame) => frame,
+            Err(e) => {
+                //text is validated as it comes in, we only have to close with the right code
+                if let Some(invalid) = e
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<yaws::InvalidUtf8>())
+                {
+                    let code = invalid.close_code();
+                    writer.send_close(Some(code)).await?;
+                    reader.wait_on_close().await?;
+                    return Ok(Some(code));
+                }
+                break;
+            }
+        };
         //check if message is valid
         // then check if is control, handle immidiatly
         // if not control check if in continue stream rn, assert that same type
         // if not respond and/or set continue stream properly
-        if !frame.is_valid() {
-            writer.send_close(Some(1002)).await?;
+        if let Err(e) = frame.validate(role, yaws::Extensions::none()) {
+            let code = e.close_code();
+            writer.send_close(Some(code)).await?;
             reader.wait_on_close().await?;
-            return Ok(Some(1002));
+            return Ok(Some(code));
         } else {
             // println!("{:?}", frame);
             match frame.opcode() {
@@ -44,14 +62,9 @@ async fn do_case(case: usize) -> Result<Option<u16>, std::io::Error> {
                         current_continue = Some(frame.opcode());
                         response_buffer = frame.masked_data().to_owned();
                     } else {
-                        let message_str = std::str::from_utf8(frame.masked_data());
-                        if message_str.is_err() {
-                            writer.send_close(Some(1007)).await?;
-                            reader.wait_on_close().await?;
-                            return Ok(Some(1007));
-                        } else {
-                            writer.send_str(message_str.unwrap()).await?
-                        }
+                        let message_str = std::str::from_utf8(frame.masked_data())
+                            .expect("text is validated by read_message");
+                        writer.send_str(message_str).await?
                     }
                 }
                 yaws::Opcode::Continue => {
@@ -65,14 +78,9 @@ async fn do_case(case: usize) -> Result<Option<u16>, std::io::Error> {
                         match current_continue {
                             Some(yaws::Opcode::Text) => {
                                 current_continue = None;
-                                let message_str = std::str::from_utf8(&response_buffer);
-                                if message_str.is_err() {
-                                    writer.send_close(Some(1007)).await?;
-                                    reader.wait_on_close().await?;
-                                    return Ok(Some(1007));
-                                } else {
-                                    writer.send_str(message_str.unwrap()).await?
-                                }
+                                let message_str = std::str::from_utf8(&response_buffer)
+                                    .expect("text is validated by read_message");
+                                writer.send_str(message_str).await?
                             }
                             Some(yaws::Opcode::Binary) => {
                                 current_continue = None;
@@ -82,21 +90,11 @@ async fn do_case(case: usize) -> Result<Option<u16>, std::io::Error> {
                         }
                     }
                 }
-                yaws::Opcode::Ping => {
-                    writer.pong(Some(&frame.masked_data())).await?;
-                }
-                yaws::Opcode::Pong => {}
+                //the reader sends pongs and the close reply itself
+                yaws::Opcode::Ping | yaws::Opcode::Pong => {}
                 yaws::Opcode::Close => {
-                    let data = frame.masked_data();
-                    if data.len() >= 2 {
-                        close_code = Some(u16::from_be_bytes([data[0], data[1]]));
-                        writer.send_close(close_code).await?;
-                        return Ok(close_code);
-                    } else {
-                        // println!("closing with none");
-                        writer.send_close(None).await?;
-                        return Ok(close_code);
-                    }
+                    close_code = frame.close_code();
+                    return Ok(close_code);
                 }
                 yaws::Opcode::Invalid(_) => {
                     writer.send_close(Some(1000)).await?;
@@ -110,7 +108,6 @@ async fn do_case(case: usize) -> Result<Option<u16>, std::io::Error> {
 async fn do_cases(id: usize, cases: Vec<usize>) -> Vec<Result<Option<u16>, std::io::Error>> {
     let mut ret = vec![];
     for case in cases {
-        tokio::task::yield_now().await;
         let result = do_case(case).await;
         println!("({:2}) {} => {:?}", id, case, result);
         ret.push(result);
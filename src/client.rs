@@ -1,278 +1,445 @@
 //rust TLS for TLS on handshake and socket + HTTP/HTTP_types for connecting on
 
-use crate::frame::Frame;
-use http::{uri::Builder, Uri};
+use crate::deflate::{self, DeflateParams, DeflatePolicy, Deflater, Inflater};
+use crate::frame::{Frame, Opcode, Role, WsParsingError};
+use crate::message::Message;
+use crate::split::{Replies, WriteHalf};
+use crate::timeout::{IdleTimer, Timeout, Timeouts};
+use crate::utf8::{InvalidUtf8, Utf8Validator};
+use crate::validation::{Extensions, FrameError, LengthEncoding, TooBig};
+use bytes::{buf::BufExt, Buf, BytesMut};
+use futures_util::{future::poll_fn, ready, sink::Sink, stream::Stream as FuturesStream};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use tokio::net::TcpStream;
-use tokio_rustls::{client::TlsStream, rustls::ClientConfig, TlsConnector};
+use tokio::sync::watch;
+use tokio_rustls::client::TlsStream;
 
-use tokio::io::{
-    split, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
-};
+use tokio::io::{split, AsyncRead, AsyncWrite, ReadHalf};
 
-#[derive(Clone, Debug)]
+// writes all of the buffer and flushes, for the read side of a server connection
+// to send its close frame when the server goes away.
+type WriteAll<S> =
+    fn(Pin<&mut S>, &mut Context<'_>, &mut BytesMut) -> Poll<Result<(), std::io::Error>>;
+
+fn poll_write_all<S: AsyncWrite>(
+    mut stream: Pin<&mut S>,
+    cx: &mut Context<'_>,
+    buffer: &mut BytesMut,
+) -> Poll<Result<(), std::io::Error>> {
+    while !buffer.is_empty() {
+        let written = ready!(stream.as_mut().poll_write(cx, buffer))?;
+        if written == 0 {
+            return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+        }
+        buffer.advance(written);
+    }
+    stream.poll_flush(cx)
+}
+
+// set on connections accepted by a Server, tells them when it's shutting down.
+#[derive(Debug)]
+struct GoingAway<S> {
+    //the reason to close with, None while the server is running
+    signal: watch::Receiver<Option<String>>,
+    //only on clients that can write, split readers hand the close to their writer
+    write: Option<WriteAll<S>>,
+    //the close frame is in the write buffer, waiting to be written
+    closing: bool,
+}
+
+#[derive(Debug)]
 pub struct Client<S> {
     stream: S,
+    //decides how we mask outgoing frames and which incoming frames are valid
+    role: Role,
+    length_encoding: LengthEncoding,
+    //frames and messages past these fail the read instead of being buffered
+    max_frame_size: usize,
+    max_message_size: usize,
     read_buffer: Vec<u8>,
     read_buffer_head: usize,
     parse_buffer_head: usize,
+    //set while we're receiving the fragments of a text message
+    text_validator: Option<Utf8Validator>,
+    //fragments of the message being reassembled for the Stream impl
+    message_buffer: Vec<u8>,
+    message_opcode: Option<Opcode>,
+    //set while we're receiving a message compressed with permessage-deflate
+    inflating: bool,
+    //frames that have been encoded but not yet written to the stream
+    write_buffer: BytesMut,
+    //only set on the reader half returned by `split`, it writes pongs and close replies through it
+    replies: Option<Arc<dyn Replies>>,
+    //shared by the halves of a split client, a connection only gets one close frame
+    close_sent: Arc<AtomicBool>,
+    going_away: Option<GoingAway<S>>,
+    //permessage-deflate, when it was negotiated. Split halves only get the side they need
+    deflate: Option<DeflateParams>,
+    deflater: Option<Deflater>,
+    inflater: Option<Inflater>,
+    timeouts: Timeouts,
+    read_timer: IdleTimer,
+    write_timer: IdleTimer,
 }
 
-impl Client<TcpStream> {
-    pub async fn connect_insecure<U: Into<String>>(uri: U) -> Result<Self, std::io::Error> {
-        //TODO infer port from scheme if missing. if scheme is missing default to wss://?
-        let uri: Uri = uri.into().parse().unwrap();
-        let host = uri.host().unwrap();
-        let scheme = match uri.scheme_str() {
-            Some("ws") | None => "ws",
-            Some(scheme) => panic!("Invalid scheme \"{}\" in uri! ", scheme),
-        };
-        let authority = if uri.port_u16().is_some() {
-            uri.authority().unwrap().as_str().to_owned()
-        } else {
-            let mut authority = host.to_owned();
-            authority.push(':');
-            authority.push_str(match scheme {
-                "wss" => "443",
-                "ws" => "80",
-                _ => unreachable!(),
-            });
-            authority
-        };
-        let path_and_query = uri.path_and_query().unwrap();
-        let uri = Builder::new()
-            .authority(authority.as_str())
-            .path_and_query(path_and_query.as_str())
-            .scheme(scheme)
-            .build()
-            .unwrap();
-        //https://docs.rs/async-tls/0.7.0/async_tls/struct.TlsConnector.html
-        // println!("Connecting to {}", uri);
-        let mut stream = TcpStream::connect(uri.authority().unwrap().as_str()).await?;
-        // println!("Connected to authority");
-        //let (sink, source) = encrypted_stream.split();
-        let upgrade_request = format!(
-            "GET {} HTTP/1.1\r
-Host: {}\r
-Connection: Upgrade\r
-Upgrade: websocket\r
-Sec-Websocket-Version: 13\r
-Sec-Websocket-Key: {}\r\n\r\n",
-            uri.path_and_query().unwrap(),
-            authority,
-            "dGhlIHNhbXBsZSBub25jZQ=="
-        );
-        // println!("request\n=======\n{}", upgrade_request);
-        stream.write_all(upgrade_request.as_bytes()).await?;
-        let mut header_pairs = std::collections::HashMap::new();
-        let mut buffered = BufReader::new(stream);
-        {
-            let mut line = String::new();
-            buffered.read_line(&mut line).await?;
-            // println!("received header line: {}", line);
-            let split = line.split(" ").collect::<Vec<_>>();
-            assert_eq!("HTTP/1.1", split[0]);
-            assert_eq!("101", split[1]);
-            line.clear();
-            while buffered.read_line(&mut line).await? > 2 {
-                let split = line.splitn(2, ":").collect::<Vec<_>>();
-                // println!("Key: \"{}\"", split[0].to_lowercase());
-                // println!("Value: \"{}\"", split[1].trim());
-                //todo: header can have duplicate keys.
-                assert_eq!(
-                    None,
-                    header_pairs.insert(
-                        split[0].to_lowercase().to_owned(),
-                        split[1].trim().to_owned(),
-                    )
-                );
-                line.clear();
+impl<S> Client<S> {
+    pub(crate) fn from_stream(
+        stream: S,
+        role: Role,
+        read_buffer: Vec<u8>,
+        read_buffer_head: usize,
+    ) -> Self {
+        Client {
+            stream,
+            role,
+            length_encoding: LengthEncoding::default(),
+            max_frame_size: 16 << 20,
+            max_message_size: 16 << 20,
+            read_buffer,
+            read_buffer_head,
+            parse_buffer_head: 0,
+            text_validator: None,
+            message_buffer: vec![],
+            message_opcode: None,
+            inflating: false,
+            write_buffer: BytesMut::new(),
+            replies: None,
+            close_sent: Arc::default(),
+            going_away: None,
+            deflate: None,
+            deflater: None,
+            inflater: None,
+            timeouts: Timeouts::default(),
+            read_timer: IdleTimer::default(),
+            write_timer: IdleTimer::default(),
+        }
+    }
+}
+
+impl<S> Client<S> {
+    // the 1001 close to send once the server has started shutting down. None while it's
+    // running, and from then on when a close has gone out already.
+    fn going_away_frame(&mut self, cx: &mut Context<'_>) -> Option<BytesMut> {
+        let going_away = self.going_away.as_mut()?;
+        let reason = loop {
+            match going_away.signal.poll_recv_ref(cx) {
+                Poll::Ready(Some(reason)) => {
+                    if let Some(reason) = &*reason {
+                        break reason.clone();
+                    }
+                }
+                //still running, or the server was dropped without shutting down
+                Poll::Ready(None) | Poll::Pending => return None,
             }
+        };
+        if self.close_sent.swap(true, Ordering::SeqCst) {
+            self.going_away = None;
+            return None;
         }
-        let content_length = header_pairs
-            .get("content_length")
-            .map(|s| s.parse::<usize>().unwrap())
-            .unwrap_or(0);
-        //todo skip body
-        assert_eq!(content_length, 0);
-        let vec = buffered.buffer().to_owned();
-        Ok(Client {
-            stream: buffered.into_inner(),
-            read_buffer_head: vec.len(),
-            read_buffer: vec,
-            parse_buffer_head: 0,
-        })
+        //control frames carry at most 125 bytes, 2 of them for the code
+        let mut len = reason.len().min(123);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut payload = 1001u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..len]);
+        let mut frame = BytesMut::new();
+        let mask = self.role.outgoing_mask();
+        Frame::write_into_bytes(&mut frame, Opcode::Close, true, &payload, mask);
+        Some(frame)
+    }
+    // wraps a stream that has already been through the upgrade handshake,
+    // e.g. one accepted by a server or upgraded by another http library.
+    pub fn from_upgraded(stream: S, role: Role) -> Self {
+        Client::from_stream(stream, role, vec![0u8; 4096], 0)
+    }
+    pub fn role(&self) -> Role {
+        self.role
+    }
+    // the underlying stream, e.g. to look at the certificate of a tls peer.
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+    // whether messages read through the Stream impl may use non-minimal lengths.
+    pub fn set_length_encoding(&mut self, length_encoding: LengthEncoding) {
+        self.length_encoding = length_encoding;
+    }
+    // payloads larger than this are rejected from their header, before any of it is buffered.
+    // 16 MiB by default.
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+    // the most a message may add up to across its fragments, and inflate to when it's
+    // compressed. 16 MiB by default.
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+    // only the idle read and write timeouts apply once connected.
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+    // the permessage-deflate parameters, if it was negotiated.
+    pub fn deflate(&self) -> Option<DeflateParams> {
+        self.deflate
+    }
+    pub(crate) fn set_deflate(&mut self, params: DeflateParams, policy: &DeflatePolicy) {
+        let (deflater, inflater) = deflate::pair(params, self.role, policy);
+        self.deflate = Some(params);
+        self.deflater = Some(deflater);
+        self.inflater = Some(inflater);
+    }
+}
+
+impl<S: std::marker::Unpin + AsyncWrite> Client<S> {
+    // makes the connection close with 1001 and the reason the signal carries once it's set.
+    pub(crate) fn set_going_away(&mut self, signal: watch::Receiver<Option<String>>) {
+        self.going_away = Some(GoingAway {
+            signal,
+            write: Some(poll_write_all),
+            closing: false,
+        });
     }
 }
 
 pub type SecureClient = Client<TlsStream<TcpStream>>;
 
-impl Client<TlsStream<TcpStream>> {
-    pub async fn connect_secure<U: Into<String>>(uri: U) -> Result<Self, std::io::Error> {
-        //TODO infer port from scheme if missing. if scheme is missing default to wss://?
-        let uri: Uri = uri.into().parse().unwrap();
-        let host = uri.host().unwrap();
-        let scheme = match uri.scheme_str() {
-            Some("wss") | None => "wss",
-            Some(scheme) => panic!("Invalid scheme \"{}\" in uri! ", scheme),
-        };
-        let authority = if uri.port_u16().is_some() {
-            uri.authority().unwrap().as_str().to_owned()
-        } else {
-            let mut authority = host.to_owned();
-            authority.push(':');
-            authority.push_str(match scheme {
-                "wss" => "443",
-                "ws" => "80",
-                _ => unreachable!(),
-            });
-            authority
-        };
-        let path_and_query = uri.path_and_query().unwrap();
-        let uri = Builder::new()
-            .authority(authority.as_str())
-            .path_and_query(path_and_query.as_str())
-            .scheme(scheme)
-            .build()
-            .unwrap();
-        //https://docs.rs/async-tls/0.7.0/async_tls/struct.TlsConnector.html
-        //println!("Connecting to {}", uri);
-        let tcp_stream = TcpStream::connect(uri.authority().unwrap().as_str()).await?;
-        //println!("Connected to authority");
-        let mut config = ClientConfig::new();
-        // println!(
-        //     "dns {:?} from {}",
-        //     webpki::DNSNameRef::try_from_ascii_str(host),
-        //     host
-        // );
-        config
-            .root_store
-            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-        //println!("{:?}", config.ciphersuites);
-        let connector = TlsConnector::from(std::sync::Arc::new(config));
-        let mut encrypted_stream = connector
-            .connect(
-                webpki::DNSNameRef::try_from_ascii_str(host).unwrap(),
-                tcp_stream,
-            )
-            .await?;
-        //println!("encryption added");
-        //let (sink, source) = encrypted_stream.split();
-        let upgrade_request = format!(
-            "GET {} HTTP/1.1\r
-Host: {}\r
-Connection: Upgrade\r
-Upgrade: websocket\r
-Sec-Websocket-Version: 13\r
-Sec-Websocket-Key: {}\r\n\r\n",
-            uri.path_and_query().unwrap(),
-            authority,
-            "dGhlIHNhbXBsZSBub25jZQ=="
-        );
-        //println!("request\n=======\n{}", upgrade_request);
-        encrypted_stream
-            .write_all(upgrade_request.as_bytes())
+impl<Stream: std::marker::Unpin + AsyncWrite> Client<Stream> {
+    // anything queued through the Sink goes out first, so mixing the two keeps messages in order.
+    async fn send_frame(
+        &mut self,
+        opcode: Opcode,
+        is_final: bool,
+        payload: &[u8],
+    ) -> Result<(), std::io::Error> {
+        //flushed first, that's where a going-away close is added
+        poll_fn(|cx| self.poll_write_buffer(cx)).await?;
+        if !self.check_sendable(opcode)? {
+            return self.flush().await;
+        }
+        let mask = self.role.outgoing_mask();
+        if mask.is_some() {
+            //masking needs a copy of the payload anyway, so encode it into the write buffer
+            Frame::write_into_bytes(&mut self.write_buffer, opcode, is_final, payload, mask);
+            return poll_fn(|cx| self.poll_write_buffer(cx)).await;
+        }
+        //unmasked frames go out as a vectored write of the header and the caller's payload
+        let (header, header_len) = Frame::encode_header(opcode, is_final, payload.len(), None);
+        let mut frame = (&header[..header_len]).chain(payload);
+        while frame.has_remaining() {
+            let written = poll_fn(|cx| {
+                self.poll_stream_write(cx, |stream, cx| stream.poll_write_buf(cx, &mut frame))
+            })
             .await?;
-        let mut header_pairs = std::collections::HashMap::new();
-        let mut buffered = BufReader::new(encrypted_stream);
+            if written == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+    // does one write on the stream, failing with Timeout::Write once it's been stuck for too long.
+    fn poll_stream_write<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        write: impl FnOnce(Pin<&mut Stream>, &mut Context<'_>) -> Poll<Result<T, std::io::Error>>,
+    ) -> Poll<Result<T, std::io::Error>> {
+        match write(Pin::new(&mut self.stream), cx) {
+            Poll::Ready(result) => {
+                self.write_timer.clear();
+                Poll::Ready(result)
+            }
+            Poll::Pending => self
+                .write_timer
+                .poll_expired(cx, self.timeouts.write, Timeout::Write)
+                .map(Err),
+        }
+    }
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        //handlers that only ever write get their 1001 out too. The write buffer only holds
+        //whole frames, so the close can go at the end of it
+        if self
+            .going_away
+            .as_ref()
+            .is_some_and(|going_away| !going_away.closing)
         {
-            let mut line = String::new();
-            buffered.read_line(&mut line).await?;
-            //println!("received header line: {}", line);
-            let split = line.split(" ").collect::<Vec<_>>();
-            assert_eq!("HTTP/1.1", split[0]);
-            assert_eq!("101", split[1]);
-            line.clear();
-            while buffered.read_line(&mut line).await? > 2 {
-                let split = line.splitn(2, ":").collect::<Vec<_>>();
-                //println!("Key: \"{}\"", split[0].to_lowercase());
-                //println!("Value: \"{}\"", split[1].trim());
-                //todo: header can have duplicate keys.
-                assert_eq!(
-                    None,
-                    header_pairs.insert(
-                        split[0].to_lowercase().to_owned(),
-                        split[1].trim().to_owned(),
-                    )
-                );
-                line.clear();
+            if let Some(frame) = self.going_away_frame(cx) {
+                self.write_buffer.extend_from_slice(&frame);
+                self.going_away = None;
             }
         }
-        let content_length = header_pairs
-            .get("content_length")
-            .map(|s| s.parse::<usize>().unwrap())
-            .unwrap_or(0);
-        //todo skip body
-        assert_eq!(content_length, 0);
-        Ok(Client {
-            stream: buffered.into_inner(),
-            read_buffer: vec![0u8; 4096], //needs to be atleast 2+8(+4)
-            read_buffer_head: 0,
-            parse_buffer_head: 0,
-        })
+        while !self.write_buffer.is_empty() {
+            //moved out for the duration of the write so the stream can be borrowed alongside
+            let buffer = std::mem::take(&mut self.write_buffer);
+            let written = self.poll_stream_write(cx, |stream, cx| stream.poll_write(cx, &buffer));
+            self.write_buffer = buffer;
+            let written = ready!(written)?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buffer.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+    // waits for the next item from `next_outgoing`, once everything buffered has been written
+    // and flushed. Used by the writer tasks behind `Sender` and `Hub`.
+    pub(crate) fn poll_outgoing<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        next_outgoing: impl FnOnce(&mut Context<'_>) -> Poll<Option<T>>,
+    ) -> Poll<Result<Option<T>, std::io::Error>> {
+        ready!(self.poll_write_buffer(cx))?;
+        ready!(self.poll_stream_write(cx, |stream, cx| stream.poll_flush(cx)))?;
+        next_outgoing(cx).map(Ok)
+    }
+    // a connection only gets one close frame, returns false if one was sent already.
+    fn mark_close_sent(&self) -> bool {
+        !self.close_sent.swap(true, Ordering::SeqCst)
+    }
+    // every frame goes past this before it's written. Nothing follows the close frame: a
+    // second close returns false and is skipped, anything else fails.
+    pub(crate) fn check_sendable(&self, opcode: Opcode) -> Result<bool, std::io::Error> {
+        if opcode == Opcode::Close {
+            return Ok(self.mark_close_sent());
+        }
+        if self.close_sent.load(Ordering::SeqCst) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "a close frame was sent already",
+            ));
+        }
+        Ok(true)
+    }
+    // writes out anything buffered by the Sink.
+    pub async fn flush(&mut self) -> Result<(), std::io::Error> {
+        poll_fn(|cx| self.poll_write_buffer(cx)).await?;
+        poll_fn(|cx| self.poll_stream_write(cx, |stream, cx| stream.poll_flush(cx))).await
+    }
+    // writes a frame that's already encoded, as is. e.g. one frame shared by many connections.
+    // it has to be masked the way our role requires.
+    pub async fn send_encoded(&mut self, frame: &Frame) -> Result<(), std::io::Error> {
+        if frame.has_mask() != self.role.outgoing_mask().is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "frame is not masked the way this side of the connection has to",
+            ));
+        }
+        poll_fn(|cx| self.poll_write_buffer(cx)).await?;
+        if !self.check_sendable(frame.opcode())? {
+            return self.flush().await;
+        }
+        self.write_encoded(frame.as_bytes(), &mut 0).await
+    }
+    // writes `bytes` from `*written` on and counts as it goes, so a write that was cut short
+    // can be finished later. The hub needs that to get a frame out before closing.
+    pub(crate) async fn write_encoded(
+        &mut self,
+        bytes: &[u8],
+        written: &mut usize,
+    ) -> Result<(), std::io::Error> {
+        while *written < bytes.len() {
+            let rest = &bytes[*written..];
+            let n =
+                poll_fn(|cx| self.poll_stream_write(cx, |stream, cx| stream.poll_write(cx, rest)))
+                    .await?;
+            if n == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            *written += n;
+        }
+        Ok(())
     }
-}
-impl<Stream: std::marker::Unpin + AsyncWriteExt> Client<Stream> {
     pub async fn send_close(&mut self, code: Option<u16>) -> Result<(), std::io::Error> {
-        let frame = Frame::new_close(code, Some(0));
-        self.stream.write_all(frame.as_bytes()).await
+        let code = code.unwrap_or(1000).to_be_bytes();
+        self.send_frame(Opcode::Close, true, &code).await
     }
-    //todo: when writting the server, we don't need to set the mask so use vectored write op to save on copying
-    //maybe give the option to use mask=0 and use that here too. That would save us a copy.
     pub async fn ping(&mut self, data: Option<&[u8]>) -> Result<(), std::io::Error> {
-        let frame = Frame::new_ping(data, Some(0));
-        self.stream.write_all(frame.as_bytes()).await
+        self.send_frame(Opcode::Ping, true, data.unwrap_or(&[]))
+            .await
     }
 
     pub async fn pong(&mut self, data: Option<&[u8]>) -> Result<(), std::io::Error> {
-        let frame = Frame::new_pong(data, Some(0));
-        self.stream.write_all(frame.as_bytes()).await
+        self.send_frame(Opcode::Pong, true, data.unwrap_or(&[]))
+            .await
     }
 
+    // with permessage-deflate, buffers the message as a single compressed frame if it's
+    // over the threshold. Returns whether it did.
+    fn buffer_compressed(
+        &mut self,
+        opcode: Opcode,
+        payload: &[u8],
+    ) -> Result<bool, std::io::Error> {
+        self.check_sendable(opcode)?;
+        let compressed = match self.deflater.as_mut() {
+            Some(deflater) => deflater.compress(payload)?,
+            None => None,
+        };
+        let compressed = match compressed {
+            Some(compressed) => compressed,
+            None => return Ok(false),
+        };
+        let start = self.write_buffer.len();
+        let mask = self.role.outgoing_mask();
+        Frame::write_into_bytes(&mut self.write_buffer, opcode, true, &compressed, mask);
+        //rsv1 marks the message as compressed
+        self.write_buffer[start] |= 1 << 6;
+        Ok(true)
+    }
     pub async fn send_str<S: AsRef<str>>(&mut self, msg: S) -> Result<(), std::io::Error> {
-        let frame = Frame::new_text(msg, Some(0));
-        //println!("Sending frame: {:?}", frame);
-        self.stream.write_all(frame.as_bytes()).await
+        if self.buffer_compressed(Opcode::Text, msg.as_ref().as_bytes())? {
+            return poll_fn(|cx| self.poll_write_buffer(cx)).await;
+        }
+        self.send_frame(Opcode::Text, true, msg.as_ref().as_bytes())
+            .await
     }
     pub async fn send_binary(&mut self, msg: &[u8]) -> Result<(), std::io::Error> {
+        if self.buffer_compressed(Opcode::Binary, msg)? {
+            return poll_fn(|cx| self.poll_write_buffer(cx)).await;
+        }
         let max_frame_size: usize = 1 << 16;
         if msg.len() < max_frame_size {
-            let frame = Frame::new_binary(&msg, Some(0), true);
-            self.stream.write_all(frame.as_bytes()).await
+            self.send_frame(Opcode::Binary, true, msg).await
         } else {
-            let frame = Frame::new_binary(&msg[0..max_frame_size], Some(0), false);
-            self.stream.write_all(frame.as_bytes()).await?;
-            for start in (max_frame_size..msg.len()).step_by(max_frame_size) {
-                let frame =
-                    Frame::new_continuation(&msg[start..start + max_frame_size], Some(0), false);
-                self.stream.write_all(frame.as_bytes()).await?;
+            let mut chunks = msg.chunks(max_frame_size);
+            let first = chunks.next().unwrap();
+            self.send_frame(Opcode::Binary, false, first).await?;
+            for chunk in chunks {
+                self.send_frame(Opcode::Continue, false, chunk).await?;
             }
-            let frame = Frame::new_continuation(&[], Some(0), true);
-            self.stream.write_all(frame.as_bytes()).await?;
-            Ok(())
+            self.send_frame(Opcode::Continue, true, &[]).await
         }
     }
 }
 
-impl<Stream: std::marker::Unpin + AsyncReadExt> Client<Stream> {
+impl<Stream: std::marker::Unpin + AsyncRead> Client<Stream> {
     pub async fn wait_on_close(&mut self) -> Result<(), std::io::Error> {
-        while match self.read_message().await?.opcode() {
-            crate::frame::Opcode::Close => false,
-            _ => true,
-        } { /*spin here*/ }
+        while !self.read_message().await?.is_close() { /*spin here*/ }
         Ok(())
     }
     //rename to handle error or something, do resize when a read fills up the buffer.
     // means buffer isn't big enough to cache all incoming messages in single read call.
-    fn resize_buffer(&mut self, e: crate::frame::WsParsingError) {
+    fn resize_buffer(&mut self, e: WsParsingError) {
         match e {
-            crate::frame::WsParsingError::IncompleteHeader => {
+            WsParsingError::IncompleteHeader => {
                 //header is always small, preemptively move it back to the front of buffer
                 self.read_buffer
                     .copy_within(self.parse_buffer_head..self.read_buffer_head, 0);
-                self.read_buffer_head = self.read_buffer_head - self.parse_buffer_head;
+                self.read_buffer_head -= self.parse_buffer_head;
                 self.parse_buffer_head = 0;
             }
-            crate::frame::WsParsingError::IncompleteMessage(missing_bytes) => {
+            WsParsingError::IncompleteMessage(missing_bytes) => {
                 // println!(
                 //     "missing {} bytes and {} free",
                 //     missing_bytes,
@@ -282,7 +449,7 @@ impl<Stream: std::marker::Unpin + AsyncReadExt> Client<Stream> {
                     //message is not going to fit here, move it back to the start
                     self.read_buffer
                         .copy_within(self.parse_buffer_head..self.read_buffer_head, 0);
-                    self.read_buffer_head = self.read_buffer_head - self.parse_buffer_head;
+                    self.read_buffer_head -= self.parse_buffer_head;
                     self.parse_buffer_head = 0;
                 }
                 //if it still doesn't fit, in the buffer...
@@ -292,84 +459,436 @@ impl<Stream: std::marker::Unpin + AsyncReadExt> Client<Stream> {
                         .resize_with(self.read_buffer_head + missing_bytes, u8::default);
                 }
             }
+            WsParsingError::LengthOverflow => unreachable!("rejected by peek_frame_from_buffer"),
         }
     }
     // check if there's a valid message between parse and read head.
     // if there is,
-    fn peek_frame_from_buffer(&mut self) -> Option<&Frame> {
-        if let Err(e) =
-            { Frame::parse_slice(&self.read_buffer[self.parse_buffer_head..self.read_buffer_head]) }
-        {
-            self.resize_buffer(e);
-            None
-        } else {
-            let frame = unsafe {
-                Frame::from_slice_unchecked(
-                    &self.read_buffer[self.parse_buffer_head..self.read_buffer_head],
-                )
+    fn peek_frame_from_buffer(&mut self) -> Result<bool, std::io::Error> {
+        let buffered = &self.read_buffer[self.parse_buffer_head..self.read_buffer_head];
+        match Frame::parse_slice(buffered) {
+            Ok((frame, _)) => {
+                self.check_frame_size(frame)?;
+                Ok(true)
+            }
+            //no point waiting for a frame we could never hold
+            Err(WsParsingError::LengthOverflow) => Err(FrameError::LengthOverflow.into()),
+            Err(e @ WsParsingError::IncompleteMessage(_)) => {
+                //the header is all there, so the size is known before making room for it
+                self.check_frame_size(unsafe { Frame::from_slice_unchecked(buffered) })?;
+                self.resize_buffer(e);
+                Ok(false)
+            }
+            Err(e) => {
+                self.resize_buffer(e);
+                Ok(false)
+            }
+        }
+    }
+    fn check_frame_size(&self, frame: &Frame) -> Result<(), TooBig> {
+        if frame.data_len() > self.max_frame_size {
+            return Err(TooBig::Frame(frame.data_len(), self.max_frame_size));
+        }
+        Ok(())
+    }
+    fn check_message_size(&self, size: usize) -> Result<(), TooBig> {
+        if size > self.max_message_size {
+            return Err(TooBig::Message(size, self.max_message_size));
+        }
+        Ok(())
+    }
+    // sends a 1001 close with the server's reason once it starts shutting down. Unsplit
+    // clients write it themselves, split readers have it written between the writer's frames.
+    fn poll_going_away(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let going_away = match &self.going_away {
+            Some(going_away) => going_away,
+            None => return Poll::Ready(Ok(())),
+        };
+        if !going_away.closing {
+            let write = going_away.write;
+            let frame = match self.going_away_frame(cx) {
+                Some(frame) => frame,
+                None => return Poll::Ready(Ok(())),
             };
-            Some(frame)
+            if write.is_none() {
+                if let Some(replies) = &self.replies {
+                    replies.queue(&frame);
+                }
+                self.going_away = None;
+                return Poll::Ready(Ok(()));
+            }
+            self.write_buffer.extend_from_slice(&frame);
+            if let Some(going_away) = &mut self.going_away {
+                going_away.closing = true;
+            }
+        }
+        if let Some(write) = self
+            .going_away
+            .as_ref()
+            .and_then(|going_away| going_away.write)
+        {
+            let mut buffer = std::mem::take(&mut self.write_buffer);
+            let written = write(Pin::new(&mut self.stream), cx, &mut buffer);
+            self.write_buffer = buffer;
+            ready!(written)?;
         }
+        self.going_away = None;
+        Poll::Ready(Ok(()))
     }
-    pub async fn read_message(&mut self) -> Result<&Frame, std::io::Error> {
-        //maybe return usize of frame size instead on succes? easier to do borrows cause drops dependence.
-        while let None = self.peek_frame_from_buffer() {
-            // println!("starting read from {}", self.read_buffer_head);
-            let bytes_read = self
-                .stream
-                .read(&mut self.read_buffer[self.read_buffer_head..])
-                .await?;
-            // println!("\t Read {} bytes", bytes_read);
+    // gets the reader's replies out without blocking the read on them. If writing fails
+    // the writer half finds out soon enough.
+    fn poll_replies(&mut self, cx: &mut Context<'_>) {
+        if let Some(replies) = &self.replies {
+            let _ = replies.poll_send(cx);
+        }
+    }
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        ready!(self.poll_going_away(cx))?;
+        self.poll_replies(cx);
+        while !self.peek_frame_from_buffer()? {
+            let read = Pin::new(&mut self.stream)
+                .poll_read(cx, &mut self.read_buffer[self.read_buffer_head..]);
+            let bytes_read = match read {
+                Poll::Ready(bytes_read) => {
+                    self.read_timer.clear();
+                    bytes_read?
+                }
+                Poll::Pending => {
+                    return self
+                        .read_timer
+                        .poll_expired(cx, self.timeouts.idle_read, Timeout::IdleRead)
+                        .map(Err)
+                }
+            };
             //shortcut to zero if we've reached eof.
             if bytes_read == 0 {
-                // println!("Connection got closed!");
-                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+                return Poll::Ready(Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)));
             }
-            self.read_buffer_head = self.read_buffer_head + bytes_read;
+            self.read_buffer_head += bytes_read;
         }
+        Poll::Ready(Ok(()))
+    }
+    // consumes the frame found by poll_frame and returns where it lives in the read buffer.
+    // the bytes stay put until the next read, even if the heads get reset.
+    fn advance_frame(&mut self) -> Result<Range<usize>, std::io::Error> {
         let frame = unsafe {
-            // println!(
-            //     "Making a frame from {} to {}",
-            //     self.parse_buffer_head, self.read_buffer_head
-            // );
-            Frame::from_slice_unchecked(
-                &self.read_buffer[self.parse_buffer_head..self.read_buffer_head],
+            Frame::from_slice_unchecked_mut(
+                &mut self.read_buffer[self.parse_buffer_head..self.read_buffer_head],
             )
         };
+        frame.unmask();
+        let frame = &*frame;
+        let range = self.parse_buffer_head..self.parse_buffer_head + frame.len();
         if self.read_buffer_head == self.parse_buffer_head + frame.len() {
             self.read_buffer_head = 0;
             self.parse_buffer_head = 0;
         } else {
             self.parse_buffer_head += frame.len();
         }
-        Ok(frame)
+        //compressed text can only be checked once it's inflated
+        let compressed = match frame.opcode() {
+            Opcode::Text | Opcode::Binary => {
+                self.inflating = self.inflater.is_some() && frame.rsv1();
+                self.inflating
+            }
+            Opcode::Continue => self.inflating,
+            _ => false,
+        };
+        if !compressed {
+            Self::validate_text(&mut self.text_validator, frame)?;
+        }
+        if let Some(replies) = &self.replies {
+            if frame
+                .validate_with(self.role, Extensions::none(), self.length_encoding)
+                .is_ok()
+            {
+                match frame.opcode() {
+                    Opcode::Ping if !self.close_sent.load(Ordering::SeqCst) => {
+                        let mut reply = BytesMut::new();
+                        Message::Pong(frame.unmasked_data().to_owned())
+                            .write_into(&mut reply, self.role.outgoing_mask());
+                        replies.queue(&reply);
+                    }
+                    Opcode::Close if !self.close_sent.swap(true, Ordering::SeqCst) => {
+                        let mut reply = BytesMut::new();
+                        Message::Close(frame.close_code())
+                            .write_into(&mut reply, self.role.outgoing_mask());
+                        replies.queue(&reply);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(range)
+    }
+    pub async fn read_message(&mut self) -> Result<&Frame, std::io::Error> {
+        let range = poll_fn(|cx| {
+            ready!(self.poll_frame(cx))?;
+            let range = self.advance_frame()?;
+            self.poll_replies(cx);
+            Poll::Ready(Ok::<_, std::io::Error>(range))
+        })
+        .await?;
+        Ok(unsafe { Frame::from_slice_unchecked(&self.read_buffer[range]) })
+    }
+    // stitches fragments together, returns None while a fragmented message is incomplete.
+    fn take_message(&mut self) -> Result<Option<Message>, std::io::Error> {
+        let range = self.advance_frame()?;
+        let frame = unsafe { Frame::from_slice_unchecked(&self.read_buffer[range]) };
+        //rsv1 may only be set on the first frame of a compressed message
+        let extensions = match frame.opcode() {
+            Opcode::Text | Opcode::Binary if self.inflater.is_some() => {
+                Extensions::permessage_deflate()
+            }
+            _ => Extensions::none(),
+        };
+        frame.validate_with(self.role, extensions, self.length_encoding)?;
+        let data = frame.unmasked_data();
+        let (opcode, data) = match (frame.opcode(), self.message_opcode) {
+            (Opcode::Ping, _) => return Ok(Some(Message::Ping(data.to_owned()))),
+            (Opcode::Pong, _) => return Ok(Some(Message::Pong(data.to_owned()))),
+            (Opcode::Close, _) => return Ok(Some(Message::Close(frame.close_code()))),
+            (opcode @ Opcode::Text, None) | (opcode @ Opcode::Binary, None) => {
+                self.check_message_size(data.len())?;
+                if !frame.is_final() {
+                    self.message_opcode = Some(opcode);
+                    self.message_buffer.clear();
+                    self.message_buffer.extend_from_slice(data);
+                    return Ok(None);
+                }
+                (opcode, data.to_owned())
+            }
+            (Opcode::Continue, Some(opcode)) => {
+                self.check_message_size(self.message_buffer.len() + data.len())?;
+                self.message_buffer.extend_from_slice(data);
+                if !frame.is_final() {
+                    return Ok(None);
+                }
+                self.message_opcode = None;
+                (opcode, std::mem::take(&mut self.message_buffer))
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unexpected frame in fragmented message",
+                ))
+            }
+        };
+        let data = match self.inflater.as_mut() {
+            Some(inflater) if self.inflating => inflater.inflate(&data, self.max_message_size)?,
+            _ => data,
+        };
+        Ok(Some(match opcode {
+            Opcode::Text => Message::Text(String::from_utf8(data).map_err(|_| InvalidUtf8)?),
+            _ => Message::Binary(data),
+        }))
+    }
+    // text is checked fragment by fragment, so a bad sequence early on in a long message
+    // is reported right away instead of after the whole message has been buffered.
+    // on error the caller should close the connection with code 1007.
+    fn validate_text(
+        validator: &mut Option<Utf8Validator>,
+        frame: &Frame,
+    ) -> Result<(), std::io::Error> {
+        let result = match (frame.opcode(), validator.as_mut()) {
+            (Opcode::Text, _) => {
+                let mut text = Utf8Validator::new();
+                let result = text.feed(frame.unmasked_data());
+                *validator = Some(text);
+                result
+            }
+            (Opcode::Continue, Some(text)) => text.feed(frame.unmasked_data()),
+            _ => return Ok(()),
+        };
+        let result = match (result, frame.is_final()) {
+            (Ok(()), true) => validator.take().unwrap().finish(),
+            (Ok(()), false) => Ok(()),
+            (Err(e), _) => {
+                *validator = None;
+                Err(e)
+            }
+        };
+        result.map_err(Into::into)
     }
 }
 
 pub type SecureReader = Client<ReadHalf<TlsStream<TcpStream>>>;
 pub type SecureWriter = Client<WriteHalf<TlsStream<TcpStream>>>;
 
-impl<Stream: std::marker::Unpin + AsyncReadExt + AsyncWriteExt> Client<Stream> {
+impl<Stream: std::marker::Unpin + AsyncRead + AsyncWrite + Send + 'static> Client<Stream> {
+    // the reader answers pings and close frames by itself, writing in between the writer's
+    // frames, so they go out even while the writer sits idle.
     pub fn split(self) -> (Client<ReadHalf<Stream>>, Client<WriteHalf<Stream>>) {
         let (read, write) = split(self.stream);
+        let (write, replies) = WriteHalf::new(write);
+        //both halves watch for the server going away, whichever notices first sends the close
+        let writer_going_away = self.going_away.as_ref().map(|going_away| GoingAway {
+            signal: going_away.signal.clone(),
+            write: None,
+            closing: false,
+        });
+        let going_away = self.going_away.map(|going_away| GoingAway {
+            signal: going_away.signal,
+            write: None,
+            closing: false,
+        });
         (
             Client {
-                stream: read,
-                read_buffer: self.read_buffer,
-                read_buffer_head: self.read_buffer_head,
+                length_encoding: self.length_encoding,
+                max_frame_size: self.max_frame_size,
+                max_message_size: self.max_message_size,
+                timeouts: self.timeouts,
                 parse_buffer_head: self.parse_buffer_head,
+                text_validator: self.text_validator,
+                message_buffer: self.message_buffer,
+                message_opcode: self.message_opcode,
+                inflating: self.inflating,
+                deflate: self.deflate,
+                inflater: self.inflater,
+                replies: Some(replies),
+                close_sent: self.close_sent.clone(),
+                going_away,
+                ..Client::from_stream(read, self.role, self.read_buffer, self.read_buffer_head)
             },
             Client {
-                stream: write,
-                read_buffer: vec![],
-                read_buffer_head: 0,
-                parse_buffer_head: 0,
+                length_encoding: self.length_encoding,
+                timeouts: self.timeouts,
+                write_buffer: self.write_buffer,
+                deflate: self.deflate,
+                deflater: self.deflater,
+                close_sent: self.close_sent,
+                going_away: writer_going_away,
+                ..Client::from_stream(write, self.role, vec![], 0)
             },
         )
     }
+}
 
+impl<Stream: std::marker::Unpin + AsyncRead + AsyncWrite> Client<Stream> {
     pub async fn close(&mut self, code: Option<u16>) -> Result<(), std::io::Error> {
         self.send_close(code).await?;
         self.wait_on_close().await
     }
 }
+
+// the halves given to `Client::reunite` didn't come from the same `split`.
+pub struct ReuniteError<S>(pub Client<ReadHalf<S>>, pub Client<WriteHalf<S>>);
+
+impl<S> std::fmt::Debug for ReuniteError<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReuniteError(..)")
+    }
+}
+
+impl<S> std::fmt::Display for ReuniteError<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tried to reunite halves of different clients")
+    }
+}
+
+impl<S> std::error::Error for ReuniteError<S> {}
+
+impl<Stream: std::marker::Unpin + AsyncRead + AsyncWrite> Client<ReadHalf<Stream>> {
+    // undoes `split`, replies the reader queued that haven't gone out yet are kept.
+    pub fn reunite(
+        mut self,
+        writer: Client<WriteHalf<Stream>>,
+    ) -> Result<Client<Stream>, Box<ReuniteError<Stream>>> {
+        if !writer.stream.is_pair_of(&self.stream) {
+            return Err(Box::new(ReuniteError(self, writer)));
+        }
+        //the reader's handle on the write half has to go before the writer can have it back
+        drop(self.replies.take());
+        let Client {
+            stream,
+            write_buffer,
+            deflater,
+            ..
+        } = writer;
+        let (stream, mut replies) = match stream.into_inner() {
+            Ok(inner) => inner,
+            Err(_) => unreachable!("only the halves of one split share a write half"),
+        };
+        replies.extend_from_slice(&write_buffer);
+        Ok(Client {
+            length_encoding: self.length_encoding,
+            max_frame_size: self.max_frame_size,
+            max_message_size: self.max_message_size,
+            timeouts: self.timeouts,
+            parse_buffer_head: self.parse_buffer_head,
+            text_validator: self.text_validator,
+            message_buffer: self.message_buffer,
+            message_opcode: self.message_opcode,
+            inflating: self.inflating,
+            write_buffer: replies,
+            deflate: self.deflate,
+            deflater,
+            inflater: self.inflater,
+            close_sent: self.close_sent,
+            going_away: self.going_away.map(|going_away| GoingAway {
+                signal: going_away.signal,
+                write: Some(poll_write_all),
+                closing: false,
+            }),
+            ..Client::from_stream(
+                self.stream.unsplit(stream),
+                self.role,
+                self.read_buffer,
+                self.read_buffer_head,
+            )
+        })
+    }
+}
+
+impl<S: std::marker::Unpin + AsyncRead> FuturesStream for Client<S> {
+    type Item = Result<Message, std::io::Error>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Err(e) = ready!(this.poll_frame(cx)) {
+                //eof between messages ends the stream, anywhere else it's an error
+                let clean_eof = e.kind() == std::io::ErrorKind::UnexpectedEof
+                    && this.read_buffer_head == this.parse_buffer_head
+                    && this.message_opcode.is_none();
+                return Poll::Ready(if clean_eof { None } else { Some(Err(e)) });
+            }
+            let message = this.take_message().transpose();
+            this.poll_replies(cx);
+            if let Some(message) = message {
+                return Poll::Ready(Some(message));
+            }
+        }
+    }
+}
+
+impl<S: std::marker::Unpin + AsyncWrite> Sink<Message> for Client<S> {
+    type Error = std::io::Error;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_write_buffer(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        if !this.check_sendable(item.opcode())? {
+            return Ok(());
+        }
+        let compressed = match &item {
+            Message::Text(text) => this.buffer_compressed(Opcode::Text, text.as_bytes())?,
+            Message::Binary(data) => this.buffer_compressed(Opcode::Binary, data)?,
+            _ => false,
+        };
+        if !compressed {
+            item.write_into(&mut this.write_buffer, this.role.outgoing_mask());
+        }
+        Ok(())
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffer(cx))?;
+        this.poll_stream_write(cx, |stream, cx| stream.poll_flush(cx))
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buffer(cx))?;
+        this.poll_stream_write(cx, |stream, cx| stream.poll_shutdown(cx))
+    }
+}
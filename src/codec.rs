@@ -0,0 +1,119 @@
+use crate::frame::{Frame, FrameBuf, Role, WsParsingError};
+use crate::message::Message;
+use crate::validation::{Extensions, FrameError, LengthEncoding, TooBig};
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+// frames for tokio_util's Framed, for when you want to handle framing yourself
+// over some transport that isn't covered by Client.
+#[derive(Debug, Clone, Copy)]
+pub struct WsCodec {
+    role: Role,
+    max_frame_size: usize,
+    //what decoded frames are validated against, the same as Client does
+    extensions: Extensions,
+    length_encoding: LengthEncoding,
+}
+
+impl WsCodec {
+    pub fn new(role: Role) -> Self {
+        WsCodec {
+            role,
+            max_frame_size: 16 << 20,
+            extensions: Extensions::none(),
+            length_encoding: LengthEncoding::default(),
+        }
+    }
+    // the reserved bits an extension on top of the codec has claimed, e.g. for compression.
+    pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+    pub fn with_length_encoding(mut self, length_encoding: LengthEncoding) -> Self {
+        self.length_encoding = length_encoding;
+        self
+    }
+    // frames with a payload larger than this are rejected before they're buffered.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+    pub fn role(&self) -> Role {
+        self.role
+    }
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+    // what can be checked from the header alone, before the payload is buffered.
+    fn check_header(&self, frame: &Frame) -> Result<(), std::io::Error> {
+        if frame.data_len() > self.max_frame_size {
+            return Err(TooBig::Frame(frame.data_len(), self.max_frame_size).into());
+        }
+        match (self.role.expects_masked(), frame.has_mask()) {
+            (false, true) => Err(FrameError::MaskedFrame.into()),
+            (true, false) => Err(FrameError::UnmaskedFrame.into()),
+            _ => Ok(()),
+        }
+    }
+    // frames we encode have to be masked the way our role requires, like Client::send_encoded.
+    fn check_outgoing(&self, frame: &Frame) -> Result<(), std::io::Error> {
+        if frame.has_mask() != self.role.outgoing_mask().is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "frame is not masked the way this side of the connection has to",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for WsCodec {
+    type Item = FrameBuf;
+    type Error = std::io::Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match Frame::parse_slice(src) {
+            Ok((frame, _)) => {
+                self.check_header(frame)?;
+                frame.validate_with(self.role, self.extensions, self.length_encoding)?;
+                let len = frame.len();
+                let mut bytes = src.split_to(len);
+                unsafe { Frame::from_slice_unchecked_mut(&mut bytes) }.unmask();
+                Ok(Some(FrameBuf::from_bytes_unchecked(bytes.freeze())))
+            }
+            Err(WsParsingError::IncompleteHeader) => Ok(None),
+            Err(WsParsingError::LengthOverflow) => Err(FrameError::LengthOverflow.into()),
+            Err(WsParsingError::IncompleteMessage(missing_bytes)) => {
+                //the header is complete, so we can bail out on oversized frames early
+                self.check_header(unsafe { Frame::from_slice_unchecked(src) })?;
+                src.reserve(missing_bytes);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder<Box<Frame>> for WsCodec {
+    type Error = std::io::Error;
+    fn encode(&mut self, item: Box<Frame>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.check_outgoing(&item)?;
+        dst.extend_from_slice(item.as_bytes());
+        Ok(())
+    }
+}
+
+impl Encoder<FrameBuf> for WsCodec {
+    type Error = std::io::Error;
+    fn encode(&mut self, item: FrameBuf, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.check_outgoing(&item)?;
+        dst.extend_from_slice(item.as_bytes());
+        Ok(())
+    }
+}
+
+impl Encoder<Message> for WsCodec {
+    type Error = std::io::Error;
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.write_into(dst, self.role.outgoing_mask());
+        Ok(())
+    }
+}
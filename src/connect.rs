@@ -0,0 +1,391 @@
+use crate::client::Client;
+use crate::frame::Role;
+use crate::resolve::{connect_any, Resolver, SystemResolver};
+use crate::timeout::{with_timeout, Timeout, Timeouts};
+use crate::verify::IpAddrVerifier;
+use http::Uri;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::{client::TlsStream, rustls::ClientConfig, TlsConnector};
+
+// everything about dialing that isn't in the uri.
+#[derive(Clone)]
+pub struct ConnectOptions {
+    //also used for the connection afterwards, see Client::set_timeouts
+    pub timeouts: Timeouts,
+    //None uses the SystemResolver
+    pub resolver: Option<Arc<dyn Resolver>>,
+    //how long an attempt gets before the next address is tried alongside it
+    pub attempt_delay: Duration,
+    //the name to expect on the server's certificate instead of the host in the uri.
+    //an ip address is looked for in the certificate's iPAddress subjectAltNames
+    pub server_name: Option<String>,
+    //None trusts the mozilla root certificates. For ip addresses only its roots are used,
+    //the certificate verifier is replaced by one that checks the address
+    pub tls_config: Option<Arc<ClientConfig>>,
+    //the Host header to send, defaults to the authority of the uri or localhost for ws+unix
+    pub host: Option<String>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            timeouts: Timeouts::default(),
+            resolver: None,
+            //the recommended value from RFC 8305 8
+            attempt_delay: Duration::from_millis(250),
+            server_name: None,
+            tls_config: None,
+            host: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for ConnectOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectOptions")
+            .field("timeouts", &self.timeouts)
+            .field("resolver", &self.resolver)
+            .field("attempt_delay", &self.attempt_delay)
+            .field("server_name", &self.server_name)
+            .field("tls_config", &self.tls_config.as_ref().map(|_| ".."))
+            .field("host", &self.host)
+            .finish()
+    }
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+    pub fn with_resolver<R: Resolver + 'static>(mut self, resolver: R) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+    pub fn with_attempt_delay(mut self, attempt_delay: Duration) -> Self {
+        self.attempt_delay = attempt_delay;
+        self
+    }
+    pub fn with_server_name<S: Into<String>>(mut self, server_name: S) -> Self {
+        self.server_name = Some(server_name.into());
+        self
+    }
+    pub fn with_tls_config(mut self, tls_config: Arc<ClientConfig>) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+    pub fn with_host<S: Into<String>>(mut self, host: S) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+}
+
+// where to connect to, taken apart from the uri.
+struct Target {
+    path_and_query: String,
+    //host:port as sent in the Host header, ipv6 hosts keep their brackets
+    authority: String,
+    //the host to resolve, ipv6 literals without brackets
+    host: String,
+    port: u16,
+}
+
+impl Target {
+    fn path_and_query(&self) -> &str {
+        &self.path_and_query
+    }
+    fn host_header<'a>(&'a self, options: &'a ConnectOptions) -> &'a str {
+        options.host.as_deref().unwrap_or(&self.authority)
+    }
+}
+
+fn invalid_uri(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, reason)
+}
+
+// checks the scheme and fills in the default port for it.
+fn parse_uri(uri: String, scheme: &str) -> Result<Target, std::io::Error> {
+    let parsed: Uri = uri
+        .parse()
+        .map_err(|e| invalid_uri(format!("invalid uri {}: {}", uri, e)))?;
+    let host = parsed
+        .host()
+        .ok_or_else(|| invalid_uri(format!("no host in uri {}", uri)))?;
+    match parsed.scheme_str() {
+        Some(s) if s == scheme => {}
+        None => {}
+        Some(other) => {
+            return Err(invalid_uri(format!(
+                "invalid scheme \"{}\" in uri {}, expected {}",
+                other, uri, scheme
+            )))
+        }
+    };
+    let port = parsed
+        .port_u16()
+        .unwrap_or(if scheme == "wss" { 443 } else { 80 });
+    let authority = format!("{}:{}", host, port);
+    let path_and_query = parsed
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    //an authority-only uri has an empty path, it still asks for /
+    let path_and_query = match path_and_query {
+        "" => "/".to_owned(),
+        path_and_query => path_and_query.to_owned(),
+    };
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_owned();
+    Ok(Target {
+        path_and_query,
+        authority,
+        host,
+        port,
+    })
+}
+
+// resolves the host unless it's an ip address already, then races the addresses.
+async fn dial(target: &Target, options: &ConnectOptions) -> Result<TcpStream, std::io::Error> {
+    let addrs = match target.host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, target.port)],
+        Err(_) => match &options.resolver {
+            Some(resolver) => resolver.resolve(&target.host, target.port).await?,
+            None => SystemResolver.resolve(&target.host, target.port).await?,
+        },
+    };
+    connect_any(addrs, options.attempt_delay).await
+}
+
+fn bad_response(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+// sends the upgrade request and reads the response, the stream is ready for frames after.
+async fn upgrade<S: std::marker::Unpin + AsyncRead + AsyncWrite>(
+    mut stream: S,
+    path_and_query: &str,
+    host: &str,
+) -> Result<Client<S>, std::io::Error> {
+    let upgrade_request = format!(
+        "GET {} HTTP/1.1\r
+Host: {}\r
+Connection: Upgrade\r
+Upgrade: websocket\r
+Sec-Websocket-Version: 13\r
+Sec-Websocket-Key: {}\r\n\r\n",
+        path_and_query, host, "dGhlIHNhbXBsZSBub25jZQ=="
+    );
+    // println!("request\n=======\n{}", upgrade_request);
+    stream.write_all(upgrade_request.as_bytes()).await?;
+    let mut buffered = BufReader::new(stream);
+    let mut line = String::new();
+    buffered.read_line(&mut line).await?;
+    let mut status_line = line.trim_end().splitn(3, ' ');
+    match (status_line.next(), status_line.next()) {
+        (Some("HTTP/1.1"), Some("101")) => {}
+        (Some(version), Some(_)) if version.starts_with("HTTP/") => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!(
+                    "server answered \"{}\" instead of upgrading",
+                    line.trim_end()
+                ),
+            ))
+        }
+        _ => return Err(bad_response(format!("invalid status line {:?}", line))),
+    }
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if buffered.read_line(&mut line).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        let (name, value) = match (header.next(), header.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => return Err(bad_response(format!("invalid header line {:?}", line))),
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse::<usize>()
+                .map_err(|_| bad_response(format!("invalid content-length {:?}", value)))?;
+        }
+    }
+    //the frames start right after the headers, a body would end up in between
+    if content_length != 0 {
+        return Err(bad_response(format!(
+            "a 101 response can't have a body, got content-length {}",
+            content_length
+        )));
+    }
+    //keep whatever the server sent after the handshake, but leave room to read into.
+    let mut vec = buffered.buffer().to_owned();
+    let read_buffer_head = vec.len();
+    vec.resize(read_buffer_head.max(4096), 0); //needs to be atleast 2+8(+4)
+    Ok(Client::from_stream(
+        buffered.into_inner(),
+        Role::Client,
+        vec,
+        read_buffer_head,
+    ))
+}
+
+impl Client<TcpStream> {
+    pub async fn connect_insecure<U: Into<String>>(uri: U) -> Result<Self, std::io::Error> {
+        Self::connect_insecure_with(uri, &ConnectOptions::default()).await
+    }
+    pub async fn connect_insecure_with<U: Into<String>>(
+        uri: U,
+        options: &ConnectOptions,
+    ) -> Result<Self, std::io::Error> {
+        let timeouts = options.timeouts;
+        let target = parse_uri(uri.into(), "ws")?;
+        let stream =
+            with_timeout(timeouts.connect, Timeout::Connect, dial(&target, options)).await?;
+        let mut client = with_timeout(
+            timeouts.upgrade,
+            Timeout::Upgrade,
+            upgrade(stream, target.path_and_query(), target.host_header(options)),
+        )
+        .await?;
+        client.set_timeouts(timeouts);
+        Ok(client)
+    }
+}
+
+impl Client<TlsStream<TcpStream>> {
+    pub async fn connect_secure<U: Into<String>>(uri: U) -> Result<Self, std::io::Error> {
+        Self::connect_secure_with(uri, &ConnectOptions::default()).await
+    }
+    pub async fn connect_secure_with<U: Into<String>>(
+        uri: U,
+        options: &ConnectOptions,
+    ) -> Result<Self, std::io::Error> {
+        let timeouts = options.timeouts;
+        let target = parse_uri(uri.into(), "wss")?;
+        let server_name = options.server_name.as_deref().unwrap_or(&target.host);
+        let ip = server_name.parse::<IpAddr>().ok();
+        //webpki wants a dns name either way. For an ip it's not sent or checked, but resumed
+        //sessions are filed under it and skip the certificate check, so it's per address
+        let ip_name = ip.map(|ip| {
+            let octets = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            let hex: String = octets.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{}.ip-address.invalid", hex)
+        });
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(match &ip_name {
+            Some(ip_name) => ip_name,
+            None => server_name,
+        })
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid server name {}", server_name),
+            )
+        })?;
+        //https://docs.rs/async-tls/0.7.0/async_tls/struct.TlsConnector.html
+        let tcp_stream =
+            with_timeout(timeouts.connect, Timeout::Connect, dial(&target, options)).await?;
+        let config = options.tls_config.clone().unwrap_or_else(|| {
+            let mut config = ClientConfig::new();
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            Arc::new(config)
+        });
+        let config = match ip {
+            Some(ip) => {
+                let mut config = (*config).clone();
+                //sni can't carry an ip address
+                config.enable_sni = false;
+                config
+                    .dangerous()
+                    .set_certificate_verifier(Arc::new(IpAddrVerifier { ip }));
+                Arc::new(config)
+            }
+            None => config,
+        };
+        let connector = TlsConnector::from(config);
+        let encrypted_stream = with_timeout(
+            timeouts.tls_handshake,
+            Timeout::TlsHandshake,
+            connector.connect(dns_name, tcp_stream),
+        )
+        .await?;
+        let mut client = with_timeout(
+            timeouts.upgrade,
+            Timeout::Upgrade,
+            upgrade(
+                encrypted_stream,
+                target.path_and_query(),
+                target.host_header(options),
+            ),
+        )
+        .await?;
+        client.set_timeouts(timeouts);
+        Ok(client)
+    }
+}
+
+// takes `ws+unix:///path/to/socket:/request/path` apart into the socket and the request path.
+// everything up to the first colon is the socket, the request path defaults to /.
+#[cfg(unix)]
+fn parse_unix_uri(uri: &str) -> Result<(&str, &str), std::io::Error> {
+    let rest = uri.strip_prefix("ws+unix://").ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("expected a ws+unix:// uri, got {}", uri),
+        )
+    })?;
+    Ok(match rest.find(':') {
+        Some(colon) if colon + 1 < rest.len() => (&rest[..colon], &rest[colon + 1..]),
+        Some(colon) => (&rest[..colon], "/"),
+        None => (rest, "/"),
+    })
+}
+
+#[cfg(unix)]
+impl Client<UnixStream> {
+    // connects over a unix domain socket, e.g. `ws+unix:///var/run/app.sock:/events`.
+    pub async fn connect_unix<U: AsRef<str>>(uri: U) -> Result<Self, std::io::Error> {
+        Self::connect_unix_with(uri, &ConnectOptions::default()).await
+    }
+    // only the connect, upgrade and post-connect timeouts and the host apply here.
+    pub async fn connect_unix_with<U: AsRef<str>>(
+        uri: U,
+        options: &ConnectOptions,
+    ) -> Result<Self, std::io::Error> {
+        let timeouts = options.timeouts;
+        let (socket, path_and_query) = parse_unix_uri(uri.as_ref())?;
+        let stream = with_timeout(
+            timeouts.connect,
+            Timeout::Connect,
+            UnixStream::connect(socket),
+        )
+        .await?;
+        let host = options.host.as_deref().unwrap_or("localhost");
+        let mut client = with_timeout(
+            timeouts.upgrade,
+            Timeout::Upgrade,
+            upgrade(stream, path_and_query, host),
+        )
+        .await?;
+        client.set_timeouts(timeouts);
+        Ok(client)
+    }
+}
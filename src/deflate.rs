@@ -0,0 +1,671 @@
+// permessage-deflate (RFC 7692): negotiating it on the server side, and compressing and
+// inflating whole messages once it's on.
+use crate::frame::Role;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use http::header::HeaderMap;
+use std::fmt::Display;
+
+// every compressed message is a deflate block flushed with Z_SYNC_FLUSH, minus this tail.
+const SYNC_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// what a server agrees to when a client offers permessage-deflate. The defaults accept
+// any offer and compress everything from 64 bytes up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflatePolicy {
+    //the largest window we compress with, 9 to 15. zlib can't do 8, offers that insist
+    //on it are declined
+    pub server_max_window_bits: u8,
+    //the largest window we let clients compress with, 8 to 15. Offers from clients that
+    //can't be limited to it are declined
+    pub client_max_window_bits: u8,
+    //start every message we send with an empty window, trading ratio for memory
+    pub server_no_context_takeover: bool,
+    //ask clients to do the same
+    pub client_no_context_takeover: bool,
+    //messages we send that are smaller than this go out uncompressed
+    pub threshold: usize,
+    //the most a single message may inflate to, past it the connection fails with 1009.
+    //16 MiB by default. None leaves it to the Client's max message size, which applies either way
+    pub max_inflated_size: Option<usize>,
+}
+
+impl Default for DeflatePolicy {
+    fn default() -> Self {
+        DeflatePolicy {
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            threshold: 64,
+            max_inflated_size: Some(16 << 20),
+        }
+    }
+}
+
+impl DeflatePolicy {
+    pub fn with_server_max_window_bits(mut self, bits: u8) -> Self {
+        self.server_max_window_bits = bits.clamp(9, 15);
+        self
+    }
+    pub fn with_client_max_window_bits(mut self, bits: u8) -> Self {
+        self.client_max_window_bits = bits.clamp(8, 15);
+        self
+    }
+    pub fn with_server_no_context_takeover(mut self) -> Self {
+        self.server_no_context_takeover = true;
+        self
+    }
+    pub fn with_client_no_context_takeover(mut self) -> Self {
+        self.client_no_context_takeover = true;
+        self
+    }
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+    pub fn with_max_inflated_size(mut self, max: usize) -> Self {
+        self.max_inflated_size = Some(max);
+        self
+    }
+}
+
+// the parameters both sides agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    pub server_max_window_bits: u8,
+    pub client_max_window_bits: u8,
+    pub server_no_context_takeover: bool,
+    pub client_no_context_takeover: bool,
+}
+
+impl Display for DeflateParams {
+    // as the Sec-WebSocket-Extensions value of the response.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "permessage-deflate")?;
+        if self.server_no_context_takeover {
+            write!(f, "; server_no_context_takeover")?;
+        }
+        if self.client_no_context_takeover {
+            write!(f, "; client_no_context_takeover")?;
+        }
+        if self.server_max_window_bits < 15 {
+            write!(
+                f,
+                "; server_max_window_bits={}",
+                self.server_max_window_bits
+            )?;
+        }
+        if self.client_max_window_bits < 15 {
+            write!(
+                f,
+                "; client_max_window_bits={}",
+                self.client_max_window_bits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// the parameters of one offer, None if it's malformed: unknown or repeated parameters,
+// or values out of range.
+#[derive(Debug, Default)]
+struct Offer {
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    server_max_window_bits: Option<u8>,
+    //Some(None) when offered without a value
+    client_max_window_bits: Option<Option<u8>>,
+}
+
+fn window_bits(value: Option<&str>) -> Option<u8> {
+    let value = value?.trim_matches('"');
+    //no leading zeros or signs, RFC 7692 7.1.2.1
+    if value.starts_with('0') || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+fn parse_offer<'a>(params: impl Iterator<Item = &'a str>) -> Option<Offer> {
+    let mut offer = Offer::default();
+    for param in params {
+        let mut split = param.splitn(2, '=');
+        let name = split.next()?.trim();
+        let value = split.next().map(str::trim);
+        match (name, value) {
+            ("server_no_context_takeover", None) if !offer.server_no_context_takeover => {
+                offer.server_no_context_takeover = true
+            }
+            ("client_no_context_takeover", None) if !offer.client_no_context_takeover => {
+                offer.client_no_context_takeover = true
+            }
+            ("server_max_window_bits", value) if offer.server_max_window_bits.is_none() => {
+                offer.server_max_window_bits = Some(window_bits(value)?)
+            }
+            ("client_max_window_bits", None) if offer.client_max_window_bits.is_none() => {
+                offer.client_max_window_bits = Some(None)
+            }
+            ("client_max_window_bits", value) if offer.client_max_window_bits.is_none() => {
+                offer.client_max_window_bits = Some(Some(window_bits(value)?))
+            }
+            _ => return None,
+        }
+    }
+    Some(offer)
+}
+
+fn accept_offer(offer: Offer, policy: &DeflatePolicy) -> Option<DeflateParams> {
+    let server_max_window_bits = offer
+        .server_max_window_bits
+        .map_or(policy.server_max_window_bits, |bits| {
+            bits.min(policy.server_max_window_bits)
+        });
+    if server_max_window_bits < 9 {
+        return None;
+    }
+    let client_max_window_bits = match offer.client_max_window_bits {
+        //a client that didn't say it can limit its window may use all 15 bits
+        None if policy.client_max_window_bits < 15 => return None,
+        None => 15,
+        Some(bits) => bits.unwrap_or(15).min(policy.client_max_window_bits),
+    };
+    Some(DeflateParams {
+        server_max_window_bits,
+        client_max_window_bits,
+        server_no_context_takeover: offer.server_no_context_takeover
+            || policy.server_no_context_takeover,
+        client_no_context_takeover: policy.client_no_context_takeover,
+    })
+}
+
+// picks the first permessage-deflate offer in the request's Sec-WebSocket-Extensions
+// that's well formed and fits the policy.
+pub(crate) fn negotiate(headers: &HeaderMap, policy: &DeflatePolicy) -> Option<DeflateParams> {
+    headers
+        .get_all("sec-websocket-extensions")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            if params.next()? != "permessage-deflate" {
+                return None;
+            }
+            accept_offer(parse_offer(params)?, policy)
+        })
+        .next()
+}
+
+// why a compressed message couldn't be inflated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeflateError {
+    Corrupt,
+    //inflated past DeflatePolicy::max_inflated_size or the Client's max message size
+    TooBig(usize),
+}
+
+impl DeflateError {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            DeflateError::Corrupt => 1007,
+            DeflateError::TooBig(_) => 1009,
+        }
+    }
+}
+
+impl Display for DeflateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeflateError::Corrupt => write!(f, "compressed message is not valid deflate data"),
+            DeflateError::TooBig(max) => {
+                write!(f, "compressed message inflates to more than {} bytes", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DeflateError {}
+
+impl From<DeflateError> for std::io::Error {
+    fn from(e: DeflateError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+// compresses the messages we send.
+pub(crate) struct Deflater {
+    compress: Compress,
+    no_context_takeover: bool,
+    threshold: usize,
+}
+
+impl std::fmt::Debug for Deflater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Deflater")
+            .field("no_context_takeover", &self.no_context_takeover)
+            .field("threshold", &self.threshold)
+            .finish()
+    }
+}
+
+// inflates the messages we receive.
+pub(crate) struct Inflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+    max_size: Option<usize>,
+}
+
+impl std::fmt::Debug for Inflater {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Inflater")
+            .field("no_context_takeover", &self.no_context_takeover)
+            .field("max_size", &self.max_size)
+            .finish()
+    }
+}
+
+// the two directions of a connection that agreed on `params`, from `role`'s side.
+pub(crate) fn pair(
+    params: DeflateParams,
+    role: Role,
+    policy: &DeflatePolicy,
+) -> (Deflater, Inflater) {
+    let (ours, theirs) = match role {
+        Role::Server => (
+            (
+                params.server_max_window_bits,
+                params.server_no_context_takeover,
+            ),
+            (
+                params.client_max_window_bits,
+                params.client_no_context_takeover,
+            ),
+        ),
+        Role::Client => (
+            (
+                params.client_max_window_bits,
+                params.client_no_context_takeover,
+            ),
+            (
+                params.server_max_window_bits,
+                params.server_no_context_takeover,
+            ),
+        ),
+    };
+    let deflater = Deflater {
+        //zlib bumps 8 bits to 9 for raw deflate anyway
+        compress: Compress::new_with_window_bits(Compression::default(), false, ours.0.max(9)),
+        no_context_takeover: ours.1,
+        threshold: policy.threshold,
+    };
+    let inflater = Inflater {
+        decompress: Decompress::new_with_window_bits(false, theirs.0.max(9)),
+        no_context_takeover: theirs.1,
+        max_size: policy.max_inflated_size,
+    };
+    (deflater, inflater)
+}
+
+impl Deflater {
+    // the payload of a compressed message, None for messages below the threshold.
+    pub(crate) fn compress(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
+        if payload.len() < self.threshold {
+            return Ok(None);
+        }
+        let mut compressed = Vec::with_capacity(payload.len() / 2 + 64);
+        let start = self.compress.total_in();
+        loop {
+            let consumed = (self.compress.total_in() - start) as usize;
+            if compressed.capacity() - compressed.len() < 64 {
+                compressed.reserve(compressed.capacity());
+            }
+            self.compress
+                .compress_vec(&payload[consumed..], &mut compressed, FlushCompress::Sync)
+                .map_err(std::io::Error::other)?;
+            //the flush is done once everything went in and there's still room left over
+            if (self.compress.total_in() - start) as usize == payload.len()
+                && compressed.len() < compressed.capacity()
+            {
+                break;
+            }
+        }
+        if compressed.ends_with(&SYNC_TAIL) {
+            compressed.truncate(compressed.len() - SYNC_TAIL.len());
+        }
+        //zlib flushes nothing at all for an empty message after a flush, but the tail the
+        //other side appends only reads as an empty block behind a 0x00, RFC 7692 7.2.3.6
+        if compressed.is_empty() {
+            compressed.push(0x00);
+        }
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(Some(compressed))
+    }
+}
+
+impl Inflater {
+    // `max_size` is the reader's limit on messages, the policy's may be lower.
+    pub(crate) fn inflate(
+        &mut self,
+        compressed: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<u8>, DeflateError> {
+        let max = self
+            .max_size
+            .map_or(max_size, |policy| policy.min(max_size));
+        let mut inflated = Vec::with_capacity(compressed.len() * 2 + 64);
+        for input in [compressed, &SYNC_TAIL[..]].iter() {
+            let start = self.decompress.total_in();
+            loop {
+                let consumed = (self.decompress.total_in() - start) as usize;
+                if inflated.capacity() - inflated.len() < 64 {
+                    //no need to make room for much more than the limit
+                    let room = max.saturating_sub(inflated.len()) + 64;
+                    inflated.reserve(inflated.capacity().min(room));
+                }
+                let status = self
+                    .decompress
+                    .decompress_vec(&input[consumed..], &mut inflated, FlushDecompress::Sync)
+                    .map_err(|_| DeflateError::Corrupt)?;
+                if inflated.len() > max {
+                    return Err(DeflateError::TooBig(max));
+                }
+                let consumed = (self.decompress.total_in() - start) as usize;
+                match status {
+                    //a block with BFINAL set ends the stream, the next message starts over
+                    Status::StreamEnd => {
+                        self.decompress.reset(false);
+                        return Ok(inflated);
+                    }
+                    _ if consumed == input.len() && inflated.len() < inflated.capacity() => break,
+                    Status::BufError if inflated.len() < inflated.capacity() => {
+                        return Err(DeflateError::Corrupt)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(inflated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Client;
+    use futures_util::StreamExt;
+    use http::header::HeaderValue;
+    use tokio::io::AsyncWriteExt;
+
+    fn offer(extensions: &str, policy: &DeflatePolicy) -> Option<DeflateParams> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "sec-websocket-extensions",
+            HeaderValue::from_str(extensions).unwrap(),
+        );
+        negotiate(&headers, policy)
+    }
+
+    fn accepted(extensions: &str) -> String {
+        offer(extensions, &DeflatePolicy::default())
+            .unwrap_or_else(|| panic!("{:?} was declined", extensions))
+            .to_string()
+    }
+
+    fn params(bits: u8, no_context_takeover: bool) -> DeflateParams {
+        DeflateParams {
+            server_max_window_bits: bits,
+            client_max_window_bits: bits,
+            server_no_context_takeover: no_context_takeover,
+            client_no_context_takeover: no_context_takeover,
+        }
+    }
+
+    #[test]
+    fn accepts_the_rfc_example_offers() {
+        //RFC 7692 7.1.3 and 5.2
+        assert_eq!(accepted("permessage-deflate"), "permessage-deflate");
+        assert_eq!(
+            accepted("permessage-deflate; client_max_window_bits"),
+            "permessage-deflate"
+        );
+        assert_eq!(
+            accepted("permessage-deflate; client_max_window_bits=10"),
+            "permessage-deflate; client_max_window_bits=10"
+        );
+        assert_eq!(
+            accepted("permessage-deflate; server_max_window_bits=10"),
+            "permessage-deflate; server_max_window_bits=10"
+        );
+        assert_eq!(
+            accepted("permessage-deflate; server_no_context_takeover"),
+            "permessage-deflate; server_no_context_takeover"
+        );
+        //the first offer that fits wins, other extensions are skipped
+        assert_eq!(
+            accepted(
+                "permessage-deflate; client_max_window_bits; server_max_window_bits=10, permessage-deflate; client_max_window_bits"
+            ),
+            "permessage-deflate; server_max_window_bits=10"
+        );
+        assert_eq!(
+            accepted("x-webkit-deflate-frame, permessage-deflate; client_max_window_bits=\"12\""),
+            "permessage-deflate; client_max_window_bits=12"
+        );
+        //client_no_context_takeover is ours to ask for, not theirs
+        assert_eq!(
+            accepted("permessage-deflate; client_no_context_takeover"),
+            "permessage-deflate"
+        );
+    }
+
+    #[test]
+    fn declines_malformed_offers() {
+        let policy = DeflatePolicy::default();
+        for malformed in [
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; server_max_window_bits=10; server_max_window_bits=10",
+            "permessage-deflate; client_max_window_bits; client_max_window_bits=10",
+            "permessage-deflate; server_max_window_bits=010",
+            "permessage-deflate; server_max_window_bits=+10",
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; server_max_window_bits=16",
+            "permessage-deflate; client_max_window_bits=7",
+            "permessage-deflate; server_no_context_takeover=1",
+            "permessage-deflate; mux",
+            "x-webkit-deflate-frame",
+        ] {
+            assert_eq!(offer(malformed, &policy), None, "{:?}", malformed);
+        }
+        //zlib can't compress with a 256 byte window
+        assert_eq!(
+            offer("permessage-deflate; server_max_window_bits=8", &policy),
+            None
+        );
+        //a bad offer doesn't spoil the fallback after it
+        assert_eq!(
+            offer(
+                "permessage-deflate; server_max_window_bits=8, permessage-deflate",
+                &policy
+            ),
+            Some(params(15, false))
+        );
+    }
+
+    #[test]
+    fn limits_the_client_window_only_when_it_can_be_limited() {
+        let policy = DeflatePolicy::default().with_client_max_window_bits(10);
+        assert_eq!(offer("permessage-deflate", &policy), None);
+        assert_eq!(
+            offer("permessage-deflate; client_max_window_bits", &policy)
+                .unwrap()
+                .client_max_window_bits,
+            10
+        );
+        assert_eq!(
+            offer("permessage-deflate; client_max_window_bits=9", &policy)
+                .unwrap()
+                .client_max_window_bits,
+            9
+        );
+        //our own window shrinks to what the client asks for, never grows past the policy
+        let policy = DeflatePolicy::default()
+            .with_server_max_window_bits(11)
+            .with_server_no_context_takeover();
+        let accepted = offer("permessage-deflate; server_max_window_bits=12", &policy).unwrap();
+        assert_eq!(accepted.server_max_window_bits, 11);
+        assert!(accepted.server_no_context_takeover);
+    }
+
+    // compresses `messages` from the server and inflates them on the client, returning the
+    // compressed sizes.
+    fn round_trip(params: DeflateParams, messages: &[&[u8]]) -> Vec<usize> {
+        let policy = DeflatePolicy::default().with_threshold(0);
+        let (mut deflater, _) = pair(params, Role::Server, &policy);
+        let (_, mut inflater) = pair(params, Role::Client, &policy);
+        messages
+            .iter()
+            .map(|message| {
+                let compressed = deflater.compress(message).unwrap().unwrap();
+                assert!(!compressed.ends_with(&SYNC_TAIL));
+                assert_eq!(inflater.inflate(&compressed, usize::MAX).unwrap(), *message);
+                compressed.len()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_with_context_takeover() {
+        let message = b"the quick brown fox jumps over the lazy dog, again and again and again";
+        let sizes = round_trip(params(15, false), &[message, message, b"", message]);
+        //the second copy is a back reference into the first
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+        assert!(sizes[3] < sizes[0], "{:?}", sizes);
+
+        let sizes = round_trip(params(9, false), &[&[7; 100_000], &[7; 100_000]]);
+        assert!(sizes[1] <= sizes[0], "{:?}", sizes);
+    }
+
+    #[test]
+    fn round_trips_without_context_takeover() {
+        let message = b"the quick brown fox jumps over the lazy dog, again and again and again";
+        let sizes = round_trip(params(15, true), &[message, message, b"", message]);
+        //every message starts from an empty window
+        assert_eq!(sizes[1], sizes[0]);
+        assert_eq!(sizes[3], sizes[0]);
+
+        //one side keeping its window while the other resets
+        let mixed = DeflateParams {
+            server_no_context_takeover: true,
+            ..params(15, false)
+        };
+        round_trip(mixed, &[message, message]);
+        let random: Vec<u8> = (0..50_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        round_trip(params(8, true), &[&random, &random]);
+    }
+
+    #[test]
+    fn leaves_small_messages_uncompressed() {
+        let policy = DeflatePolicy::default();
+        let (mut deflater, _) = pair(params(15, false), Role::Server, &policy);
+        assert_eq!(deflater.compress(&[1; 63]).unwrap(), None);
+        assert!(deflater.compress(&[1; 64]).unwrap().is_some());
+    }
+
+    #[test]
+    fn strips_and_restores_the_sync_tail() {
+        let policy = DeflatePolicy::default().with_threshold(0);
+        let mut compress = Compress::new(Compression::default(), false);
+        let mut flushed = Vec::with_capacity(256);
+        compress
+            .compress_vec(b"hello", &mut flushed, FlushCompress::Sync)
+            .unwrap();
+        assert!(flushed.ends_with(&SYNC_TAIL));
+
+        let (mut deflater, _) = pair(params(15, false), Role::Client, &policy);
+        let compressed = deflater.compress(b"hello").unwrap().unwrap();
+        assert_eq!(compressed, flushed[..flushed.len() - SYNC_TAIL.len()]);
+        //an empty message is a lone 0x00
+        assert_eq!(deflater.compress(b"").unwrap().unwrap(), [0x00]);
+
+        let (_, mut inflater) = pair(params(15, false), Role::Server, &policy);
+        assert_eq!(inflater.inflate(&compressed, 1024).unwrap(), b"hello");
+        assert_eq!(inflater.inflate(&[0x00], 1024).unwrap(), b"");
+        //the RFC 7692 7.2.3.1 examples, the second one ends its own stream
+        let (_, mut inflater) = pair(params(15, false), Role::Server, &policy);
+        for hello in [
+            [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+            [0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+            [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00],
+        ] {
+            assert_eq!(inflater.inflate(&hello, 1024).unwrap(), b"Hello");
+        }
+        assert_eq!(
+            inflater.inflate(&[0xff, 0xff, 0xff], 1024),
+            Err(DeflateError::Corrupt)
+        );
+    }
+
+    fn bomb() -> Vec<u8> {
+        let policy = DeflatePolicy::default();
+        let (mut deflater, _) = pair(params(15, false), Role::Server, &policy);
+        deflater.compress(&vec![0; 1 << 20]).unwrap().unwrap()
+    }
+
+    #[test]
+    fn caps_what_a_message_inflates_to() {
+        let compressed = bomb();
+        assert!(compressed.len() < 2048);
+
+        let policy = DeflatePolicy::default().with_max_inflated_size(64 << 10);
+        let (_, mut inflater) = pair(params(15, false), Role::Client, &policy);
+        let e = inflater.inflate(&compressed, 16 << 20).unwrap_err();
+        assert_eq!(e, DeflateError::TooBig(64 << 10));
+        assert_eq!(e.close_code(), 1009);
+
+        //the reader's own limit applies when it's the lower one
+        let (_, mut inflater) = pair(params(15, false), Role::Client, &policy);
+        let e = inflater.inflate(&compressed, 1000).unwrap_err();
+        assert_eq!(e, DeflateError::TooBig(1000));
+
+        let policy = DeflatePolicy {
+            max_inflated_size: None,
+            ..DeflatePolicy::default()
+        };
+        let (_, mut inflater) = pair(params(15, false), Role::Client, &policy);
+        assert_eq!(
+            inflater.inflate(&compressed, 1 << 19),
+            Err(DeflateError::TooBig(1 << 19))
+        );
+        let (_, mut inflater) = pair(params(15, false), Role::Client, &policy);
+        assert_eq!(
+            inflater.inflate(&compressed, 1 << 20).unwrap().len(),
+            1 << 20
+        );
+    }
+
+    #[tokio::test]
+    async fn the_clients_max_message_size_caps_inflated_messages() {
+        let compressed = bomb();
+        let (stream, mut peer) = tokio::io::duplex(1 << 16);
+        let mut client = Client::from_upgraded(stream, Role::Client);
+        client.set_deflate(params(15, false), &DeflatePolicy::default());
+        client.set_max_message_size(64 << 10);
+
+        //a final binary frame with rsv1 set, from the server so unmasked
+        let mut frame = vec![0xC2, 126];
+        frame.extend_from_slice(&(compressed.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&compressed);
+        peer.write_all(&frame).await.unwrap();
+        let e = client.next().await.unwrap().unwrap_err();
+        let e = *e.get_ref().unwrap().downcast_ref::<DeflateError>().unwrap();
+        assert_eq!(e, DeflateError::TooBig(64 << 10));
+        assert_eq!(e.close_code(), 1009);
+    }
+}
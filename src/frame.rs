@@ -1,4 +1,8 @@
+use crate::validation::Extensions;
+use bytes::{Bytes, BytesMut};
 use std::alloc::{alloc, Layout};
+use std::borrow::Borrow;
+use std::convert::TryFrom;
 use std::fmt::Debug;
 pub struct Frame {
     fin_rsv_opcode: u8,
@@ -16,6 +20,42 @@ pub enum Opcode {
     Invalid(u8),
 }
 
+impl Opcode {
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Opcode::Continue => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+            Opcode::Invalid(x) => x,
+        }
+    }
+}
+
+// which end of the connection we are. Clients mask everything they send and
+// servers never do, so this decides both how we write frames and what we accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    // the mask to put on outgoing frames.
+    pub fn outgoing_mask(self) -> Option<u32> {
+        match self {
+            Role::Client => Some(0),
+            Role::Server => None,
+        }
+    }
+    // whether frames coming from the other end must be masked.
+    pub fn expects_masked(self) -> bool {
+        self == Role::Server
+    }
+}
+
 pub enum ContentLength {
     EightBytes,
     TwoBytes,
@@ -33,6 +73,8 @@ pub enum WsParsingError {
     // NotAFrame,
     IncompleteHeader,
     IncompleteMessage(usize),
+    //the 64 bit length has its most significant bit set
+    LengthOverflow,
 }
 /*The fragments of one message MUST NOT be interleaved between the
       fragments of another message unless an extension has been
@@ -43,33 +85,13 @@ pub enum WsParsingError {
 
 */
 impl Frame {
+    // validity of a frame received by a client without extensions, see `validate` for the details.
     pub fn is_valid(&self) -> bool {
-        if self.rsv1()
-            || self.rsv2()
-            || self.rsv3()
-            || self.has_mask()
-            || self.data_len() > ((1 << 63) - 1)
-        {
-            false
-        } else if !match self.opcode() {
-            Opcode::Close | Opcode::Ping | Opcode::Pong => {
-                if (self.data_len() > 125) | !self.fin() {
-                    false
-                } else {
-                    true
-                }
-            }
-            Opcode::Invalid(_) => false,
-            Opcode::Binary | Opcode::Text | Opcode::Continue => true,
-        } {
-            false
-        } else {
-            match self.content_length_bytes() {
-                ContentLength::TwoBytes => (self.data_len() >= 126),
-                ContentLength::EightBytes => (self.data_len() >= (1 << 16 - 1)),
-                _ => true,
-            }
-        }
+        self.is_valid_for(Role::Client)
+    }
+    // validity of a frame received by `role` without extensions.
+    pub fn is_valid_for(&self, role: Role) -> bool {
+        self.validate(role, Extensions::none()).is_ok()
     }
     fn create_layout(dynamic_size: usize) -> Layout {
         Layout::array::<u8>(2 + dynamic_size).unwrap()
@@ -79,21 +101,33 @@ impl Frame {
         let layout = Self::create_layout(dynamic_size);
         let boxed_ptr = {
             let raw_ptr = alloc(layout);
-            if raw_ptr == std::ptr::null_mut() {
+            if raw_ptr.is_null() {
                 std::alloc::handle_alloc_error(layout);
             }
-            let raw_slice = std::slice::from_raw_parts_mut(raw_ptr, 2 + dynamic_size);
-            Box::from_raw(std::mem::transmute::<&mut [u8], *mut Self>(raw_slice))
+            //the slice length becomes the length of `dynamic`, the two header bytes aren't part of it
+            let raw_slice = std::slice::from_raw_parts_mut(raw_ptr, dynamic_size);
+            Box::from_raw(raw_slice as *mut [u8] as *mut Self)
         };
         boxed_ptr
     }
     //note, this takes the whole slice, even if the message is only a small part.
+    /// # Safety
+    /// `slice` must start with a complete frame header and be at least `frame.len()` bytes long.
     pub unsafe fn from_slice_unchecked(slice: &[u8]) -> &Frame {
         std::mem::transmute::<&[u8], &Frame>(slice)
     }
+    /// # Safety
+    /// same as `from_slice_unchecked`.
+    pub unsafe fn from_slice_unchecked_mut(slice: &mut [u8]) -> &mut Frame {
+        &mut *(slice as *mut [u8] as *mut Frame)
+    }
     pub fn len(&self) -> usize {
         self.header_size() + self.data_len()
     }
+    // a frame always has a header, it's the payload that can be empty.
+    pub fn is_empty(&self) -> bool {
+        self.data_len() == 0
+    }
     pub fn parse_slice(slice: &[u8]) -> Result<(&Frame, &[u8]), WsParsingError> {
         //the minimum WS frame size is 2.
         if slice.len() < 2 {
@@ -104,7 +138,14 @@ impl Frame {
                 //check if it's safe to read the size of the data segment
                 if slice.len() < frame.header_size() {
                     return Err(WsParsingError::IncompleteHeader);
-                } else if slice.len() < frame.len() {
+                }
+                //the most significant bit must be 0, and the frame has to be addressable at all.
+                let payload_len = frame.payload_len();
+                if payload_len >> 63 != 0 || payload_len > (usize::MAX - frame.header_size()) as u64
+                {
+                    return Err(WsParsingError::LengthOverflow);
+                }
+                if slice.len() < frame.len() {
                     return Err(WsParsingError::IncompleteMessage(frame.len() - slice.len()));
                 }
                 frame.header_size() + frame.data_len()
@@ -141,16 +182,10 @@ impl Frame {
         }
     }
     pub fn is_close(&self) -> bool {
-        match self.opcode() {
-            Opcode::Close => true,
-            _ => false,
-        }
+        matches!(self.opcode(), Opcode::Close)
     }
     pub fn is_control(&self) -> bool {
-        match self.opcode() {
-            Opcode::Close | Opcode::Ping | Opcode::Pong => true,
-            _ => false,
-        }
+        matches!(self.opcode(), Opcode::Close | Opcode::Ping | Opcode::Pong)
     }
     pub fn close_code(&self) -> Option<u16> {
         if self.is_close() && self.data_len() >= 2 {
@@ -178,7 +213,7 @@ impl Frame {
                 0
             };
             let bytes = [
-                self.dynamic[offset + 0],
+                self.dynamic[offset],
                 self.dynamic[offset + 1],
                 self.dynamic[offset + 2],
                 self.dynamic[offset + 3],
@@ -203,117 +238,169 @@ impl Frame {
         let offset = self.header_size() - 2;
         &self.dynamic[offset..offset + self.data_len()]
     }
+    // frames are unmasked in place when they're read, see `unmask`.
     pub fn unmasked_data(&self) -> &[u8] {
-        if self.has_mask() {
-            panic!("not implemented yet!");
-        } else {
-            self.masked_data()
+        match self.mask() {
+            None | Some(0) => self.masked_data(),
+            Some(_) => panic!("frame has to be unmasked before reading its data"),
+        }
+    }
+    // unmasks the payload in place and zeroes the key, so the header stays truthful.
+    pub fn unmask(&mut self) {
+        if let Some(mask) = self.mask() {
+            let offset = self.header_size() - 2;
+            let data_len = self.data_len();
+            crate::mask::apply_mask(&mut self.dynamic[offset..offset + data_len], mask);
+            self.dynamic[offset - 4..offset].copy_from_slice(&[0; 4]);
         }
     }
     pub fn is_final(&self) -> bool {
         self.fin_rsv_opcode >= 1 << 7
     }
+    // the payload length as sent, decoded as u64 before we know it fits in memory.
+    pub fn payload_len(&self) -> u64 {
+        match self.content_length_bytes() {
+            ContentLength::EightBytes => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(&self.dynamic[..8]);
+                u64::from_be_bytes(bytes)
+            }
+            ContentLength::TwoBytes => {
+                u16::from_be_bytes([self.dynamic[0], self.dynamic[1]]) as u64
+            }
+            ContentLength::OneByte(len) => len as u64,
+        }
+    }
+    // only meaningful once parse_slice has checked that the length fits in a usize.
     pub fn data_len(&self) -> usize {
-        let len = self.mask_len % 128;
-        if len == 127 {
-            let bytes = [
-                self.dynamic[0],
-                self.dynamic[1],
-                self.dynamic[2],
-                self.dynamic[3],
-                self.dynamic[4],
-                self.dynamic[5],
-                self.dynamic[6],
-                self.dynamic[7],
-            ];
-            u64::from_be_bytes(bytes) as usize
-        } else if len == 126 {
-            let bytes = [self.dynamic[0], self.dynamic[1]];
-            u16::from_be_bytes(bytes) as usize
-        } else {
-            len as usize
+        self.payload_len() as usize
+    }
+    // whether the length is encoded with the fewest bytes possible, as RFC 6455 5.2 asks.
+    pub fn has_minimal_length(&self) -> bool {
+        match self.content_length_bytes() {
+            ContentLength::EightBytes => self.payload_len() > u16::MAX as u64,
+            ContentLength::TwoBytes => self.payload_len() > 125,
+            ContentLength::OneByte(_) => true,
         }
     }
-    //TODO: make a function to generate just the header, or construct header in place.
-    // unsafeish, but can save a copy
-    fn new_raw(buf: &[u8], mask: Option<u32>, opcode: u8, is_final: bool) -> Box<Frame> {
-        let fin_rsv_opcode = {
-            if is_final {
-                1 << 7
-            } else {
-                0
-            }
-        } | opcode;
-        let payload_length: u8 = if buf.len() <= 125 {
-            buf.len() as u8
-        } else if buf.len() <= (1 << 16) - 1 {
-            126
-        } else {
-            127
-        };
-        let mask_len = if mask.is_some() {
-            (1 << 7) | payload_length
+    // total size of a frame carrying `payload_len` bytes.
+    pub fn encoded_len(payload_len: usize, masked: bool) -> usize {
+        Self::encode_header(
+            Opcode::Binary,
+            true,
+            payload_len,
+            if masked { Some(0) } else { None },
+        )
+        .1 + payload_len
+    }
+    // builds just the header, returns the buffer and how many bytes of it are used.
+    // lets us send header and payload separately without copying the payload.
+    pub fn encode_header(
+        opcode: Opcode,
+        is_final: bool,
+        payload_len: usize,
+        mask: Option<u32>,
+    ) -> ([u8; 14], usize) {
+        let mut header = [0u8; 14];
+        header[0] = if is_final { 1 << 7 } else { 0 } | opcode.as_u8();
+        let mask_bit = if mask.is_some() { 1 << 7 } else { 0 };
+        let mut header_len = 2;
+        if payload_len <= 125 {
+            header[1] = mask_bit | payload_len as u8;
+        } else if payload_len < (1 << 16) {
+            header[1] = mask_bit | 126;
+            header[2..4].copy_from_slice(&(payload_len as u16).to_be_bytes());
+            header_len += 2;
         } else {
-            payload_length
-        };
-        let dynamic_size = buf.len()
-            + if mask.is_some() { 4 } else { 0 }
-            + if payload_length == 126 {
-                2
-            } else if payload_length == 127 {
-                8
-            } else {
-                0
-            };
-        //TODO: can  probably do all of this with iterator chaining.
-        let mut this = unsafe { Self::new_boxed_uninit(dynamic_size) };
-        this.fin_rsv_opcode = fin_rsv_opcode;
-        this.mask_len = mask_len;
-        let mut payload_idx = 0;
-        if payload_length == 126 {
-            let size = (buf.len() as u16).to_be_bytes();
-            for i in 0..size.len() {
-                this.dynamic[payload_idx + i] = size[i];
-            }
-            payload_idx += 2;
-        } else if payload_length == 127 {
-            // if payload_length == 126 {
-            let size = (buf.len() as u64).to_be_bytes();
-            for i in 0..size.len() {
-                this.dynamic[payload_idx + i] = size[i];
-            }
-            payload_idx += 8;
-            // }
+            header[1] = mask_bit | 127;
+            header[2..10].copy_from_slice(&(payload_len as u64).to_be_bytes());
+            header_len += 8;
         }
-        let mask_array = mask.unwrap_or(0).to_be_bytes();
-        if mask.is_some() {
-            for i in 0..mask_array.len() {
-                this.dynamic[payload_idx + i] = mask_array[i];
-            }
-            payload_idx += 4;
+        if let Some(mask) = mask {
+            header[header_len..header_len + 4].copy_from_slice(&mask.to_be_bytes());
+            header_len += 4;
         }
-        for i in 0..buf.len() {
-            this.dynamic[payload_idx + i] = buf[i] ^ mask_array[i % 4];
+        (header, header_len)
+    }
+    fn apply_mask(data: &mut [u8], mask: Option<u32>) {
+        if let Some(mask) = mask {
+            crate::mask::apply_mask(data, mask);
         }
+    }
+    // writes a complete frame to the start of `dst`, None if it doesn't fit.
+    // use `encoded_len` to find out how much room is needed.
+    pub fn write_into<'a>(
+        dst: &'a mut [u8],
+        opcode: Opcode,
+        is_final: bool,
+        payload: &[u8],
+        mask: Option<u32>,
+    ) -> Option<&'a Frame> {
+        let (header, header_len) = Self::encode_header(opcode, is_final, payload.len(), mask);
+        let frame_len = header_len + payload.len();
+        if dst.len() < frame_len {
+            return None;
+        }
+        dst[..header_len].copy_from_slice(&header[..header_len]);
+        dst[header_len..frame_len].copy_from_slice(payload);
+        Self::apply_mask(&mut dst[header_len..frame_len], mask);
+        Some(unsafe { Self::from_slice_unchecked(&dst[..frame_len]) })
+    }
+    // appends a complete frame to `dst`, growing it if needed.
+    pub fn write_into_bytes(
+        dst: &mut BytesMut,
+        opcode: Opcode,
+        is_final: bool,
+        payload: &[u8],
+        mask: Option<u32>,
+    ) {
+        let (header, header_len) = Self::encode_header(opcode, is_final, payload.len(), mask);
+        dst.reserve(header_len + payload.len());
+        dst.extend_from_slice(&header[..header_len]);
+        let payload_start = dst.len();
+        dst.extend_from_slice(payload);
+        Self::apply_mask(&mut dst[payload_start..], mask);
+    }
+    fn new_raw(buf: &[u8], mask: Option<u32>, opcode: Opcode, is_final: bool) -> Box<Frame> {
+        let frame_len = Self::encoded_len(buf.len(), mask.is_some());
+        let mut this = unsafe { Self::new_boxed_uninit(frame_len - 2) };
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(&mut *this as *mut Frame as *mut u8, frame_len)
+        };
+        Self::write_into(bytes, opcode, is_final, buf, mask);
         this
     }
     pub fn new_close(code: Option<u16>, mask: Option<u32>) -> Box<Frame> {
-        Self::new_raw(&code.unwrap_or(1000).to_be_bytes(), mask, 0x8, true)
+        Self::new_raw(
+            &code.unwrap_or(1000).to_be_bytes(),
+            mask,
+            Opcode::Close,
+            true,
+        )
     }
     pub fn new_binary(buf: &[u8], mask: Option<u32>, is_final: bool) -> Box<Frame> {
-        Self::new_raw(buf, mask, 0x2, is_final)
+        Self::new_raw(buf, mask, Opcode::Binary, is_final)
     }
     pub fn new_continuation(buf: &[u8], mask: Option<u32>, is_final: bool) -> Box<Frame> {
-        Self::new_raw(buf, mask, 0x0, is_final)
+        Self::new_raw(buf, mask, Opcode::Continue, is_final)
     }
     pub fn new_ping(buf: Option<&[u8]>, mask: Option<u32>) -> Box<Frame> {
-        Self::new_raw(buf.unwrap_or(&[]), mask, 0x9, true)
+        Self::new_raw(buf.unwrap_or(&[]), mask, Opcode::Ping, true)
     }
     pub fn new_pong(buf: Option<&[u8]>, mask: Option<u32>) -> Box<Frame> {
-        Self::new_raw(buf.unwrap_or(&[]), mask, 0xA, true)
+        Self::new_raw(buf.unwrap_or(&[]), mask, Opcode::Pong, true)
     }
     pub fn new_text<S: AsRef<str>>(buf: S, mask: Option<u32>) -> Box<Frame> {
-        Self::new_raw(buf.as_ref().as_bytes(), mask, 0x1, true)
+        Self::new_raw(buf.as_ref().as_bytes(), mask, Opcode::Text, true)
+    }
+    // copies the frame out of whatever buffer it was parsed from.
+    pub fn to_boxed(&self) -> Box<Frame> {
+        let bytes = self.as_bytes();
+        let mut this = unsafe { Self::new_boxed_uninit(bytes.len() - 2) };
+        this.fin_rsv_opcode = self.fin_rsv_opcode;
+        this.mask_len = self.mask_len;
+        this.dynamic.copy_from_slice(&bytes[2..]);
+        this
     }
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
@@ -369,3 +456,123 @@ impl Debug for Frame {
             .finish()
     }
 }
+
+// owned counterpart of Frame, like String is to str. The bytes are reference counted
+// so cloning is cheap and frames can outlive the read buffer they were parsed from.
+#[derive(Clone)]
+pub struct FrameBuf {
+    bytes: Bytes,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum FrameBufError {
+    Parsing(WsParsingError),
+    //the buffer holds more than a single frame
+    TrailingBytes(usize),
+}
+
+impl std::fmt::Display for FrameBufError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameBufError::Parsing(WsParsingError::IncompleteHeader) => {
+                write!(f, "incomplete frame header")
+            }
+            FrameBufError::Parsing(WsParsingError::IncompleteMessage(missing)) => {
+                write!(f, "frame is missing {} bytes", missing)
+            }
+            FrameBufError::Parsing(WsParsingError::LengthOverflow) => {
+                write!(f, "payload length has its highest bit set")
+            }
+            FrameBufError::TrailingBytes(extra) => {
+                write!(f, "{} bytes left over after the frame", extra)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameBufError {}
+
+impl FrameBuf {
+    // the bytes have to hold exactly one frame.
+    pub fn from_bytes(bytes: Bytes) -> Result<Self, FrameBufError> {
+        let (_, remainder) = Frame::parse_slice(&bytes).map_err(FrameBufError::Parsing)?;
+        if !remainder.is_empty() {
+            return Err(FrameBufError::TrailingBytes(remainder.len()));
+        }
+        Ok(FrameBuf { bytes })
+    }
+    // n.b. `bytes` has to be exactly one complete frame.
+    pub(crate) fn from_bytes_unchecked(bytes: Bytes) -> Self {
+        FrameBuf { bytes }
+    }
+    pub fn as_frame(&self) -> &Frame {
+        unsafe { Frame::from_slice_unchecked(&self.bytes) }
+    }
+    pub fn into_bytes(self) -> Bytes {
+        self.bytes
+    }
+}
+
+impl std::ops::Deref for FrameBuf {
+    type Target = Frame;
+    fn deref(&self) -> &Frame {
+        self.as_frame()
+    }
+}
+
+impl AsRef<Frame> for FrameBuf {
+    fn as_ref(&self) -> &Frame {
+        self.as_frame()
+    }
+}
+
+impl AsRef<[u8]> for FrameBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Borrow<Frame> for FrameBuf {
+    fn borrow(&self) -> &Frame {
+        self.as_frame()
+    }
+}
+
+impl ToOwned for Frame {
+    type Owned = FrameBuf;
+    fn to_owned(&self) -> FrameBuf {
+        FrameBuf::from_bytes_unchecked(Bytes::copy_from_slice(self.as_bytes()))
+    }
+}
+
+impl From<&Frame> for FrameBuf {
+    fn from(frame: &Frame) -> Self {
+        frame.to_owned()
+    }
+}
+
+impl From<Box<Frame>> for FrameBuf {
+    fn from(frame: Box<Frame>) -> Self {
+        frame.as_ref().to_owned()
+    }
+}
+
+impl TryFrom<Bytes> for FrameBuf {
+    type Error = FrameBufError;
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        FrameBuf::from_bytes(bytes)
+    }
+}
+
+impl TryFrom<Vec<u8>> for FrameBuf {
+    type Error = FrameBufError;
+    fn try_from(bytes: Vec<u8>) -> Result<Self, Self::Error> {
+        FrameBuf::from_bytes(Bytes::from(bytes))
+    }
+}
+
+impl Debug for FrameBuf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_frame().fmt(f)
+    }
+}
@@ -0,0 +1,84 @@
+use crate::client::Client;
+use crate::message::Message;
+use futures_util::{future::poll_fn, sink::SinkExt};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc::{channel, Receiver};
+
+// why the writer task stopped, kept around to hand to every sender that tries after.
+type Failure = Arc<Mutex<Option<(std::io::ErrorKind, String)>>>;
+
+// a cheap, cloneable way to write to a client from many tasks at once. Messages are
+// queued for a writer task that owns the client, the queue is bounded so senders
+// wait when the connection can't keep up.
+#[derive(Debug, Clone)]
+pub struct Sender {
+    outgoing: tokio::sync::mpsc::Sender<Message>,
+    failure: Failure,
+}
+
+impl Sender {
+    // waits for room in the queue. Fails once the writer task has stopped, with the
+    // error that stopped it.
+    pub async fn send<M: Into<Message>>(&mut self, message: M) -> Result<(), std::io::Error> {
+        if self.outgoing.send(message.into()).await.is_err() {
+            return Err(self.failure());
+        }
+        Ok(())
+    }
+    pub async fn ping(&mut self, data: Option<&[u8]>) -> Result<(), std::io::Error> {
+        self.send(Message::Ping(data.unwrap_or(&[]).to_vec())).await
+    }
+    // queues a close frame, the writer task stops after sending it.
+    pub async fn close(&mut self, code: Option<u16>) -> Result<(), std::io::Error> {
+        self.send(Message::Close(code)).await
+    }
+    fn failure(&self) -> std::io::Error {
+        match &*self.failure.lock().unwrap() {
+            Some((kind, reason)) => std::io::Error::new(*kind, reason.as_str()),
+            None => std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "the connection has been closed",
+            ),
+        }
+    }
+}
+
+impl<S: std::marker::Unpin + AsyncWrite + Send + 'static> Client<S> {
+    // hands the client to a writer task, see `Sender`. Meant for the writer half of `split`,
+    // the reader half keeps answering pings and close frames in between its messages.
+    pub fn into_handle(self) -> Sender {
+        self.into_handle_with_capacity(32)
+    }
+    pub fn into_handle_with_capacity(self, capacity: usize) -> Sender {
+        let (outgoing, receiver) = channel(capacity);
+        let failure = Failure::default();
+        tokio::spawn(run_writer(self, receiver, failure.clone()));
+        Sender { outgoing, failure }
+    }
+}
+
+async fn run_writer<S: std::marker::Unpin + AsyncWrite>(
+    mut client: Client<S>,
+    mut outgoing: Receiver<Message>,
+    failure: Failure,
+) {
+    let result = async {
+        while let Some(message) =
+            poll_fn(|cx| client.poll_outgoing(cx, |cx| outgoing.poll_recv(cx))).await?
+        {
+            let is_close = message.is_close();
+            client.send(message).await?;
+            if is_close {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    }
+    .await;
+    if let Err(e) = result {
+        *failure.lock().unwrap() = Some((e.kind(), e.to_string()));
+    }
+    //the lock is released before the receiver goes, so senders that fail see the error.
+    drop(outgoing);
+}
@@ -0,0 +1,311 @@
+use crate::client::Client;
+use crate::frame::{FrameBuf, Role};
+use crate::message::Message;
+use bytes::BytesMut;
+use futures_util::future::poll_fn;
+use futures_util::SinkExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+// what to do with a member that can't keep up with what's published to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumer {
+    //skip messages while its queue is full
+    Drop,
+    //close its connection once its queue is full
+    Disconnect,
+    //let its queue grow to this many bytes, then close its connection
+    Buffer(usize),
+}
+
+// the code members are closed with when they fall too far behind.
+const SLOW_CONSUMER_CODE: u16 = 1008;
+// how long a member that fell behind gets to take the rest of its current frame and the
+// close before it's cut off.
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
+
+enum Offered {
+    Queued,
+    //skipped under SlowConsumer::Drop
+    Dropped,
+    //closed for being too slow, or the connection is already gone
+    Gone,
+}
+
+// the hub's side of a member, a copy sits in every room it joined.
+#[derive(Clone)]
+struct Queue {
+    sender: UnboundedSender<FrameBuf>,
+    //messages, or bytes for SlowConsumer::Buffer, that haven't been written yet
+    queued: Arc<AtomicUsize>,
+    //tells the member's task it fell behind, without waiting in line behind its queue
+    overflowed: Arc<Notify>,
+    policy: SlowConsumer,
+    limit: usize,
+}
+
+impl Queue {
+    fn cost(policy: SlowConsumer, frame: &FrameBuf) -> usize {
+        match policy {
+            SlowConsumer::Buffer(_) => frame.len(),
+            _ => 1,
+        }
+    }
+    fn offer(&self, frame: &FrameBuf) -> Offered {
+        let cost = Queue::cost(self.policy, frame);
+        if self.queued.load(Ordering::SeqCst) + cost > self.limit {
+            if self.policy == SlowConsumer::Drop {
+                return Offered::Dropped;
+            }
+            self.overflowed.notify();
+            return Offered::Gone;
+        }
+        self.queued.fetch_add(cost, Ordering::SeqCst);
+        match self.sender.send(frame.clone()) {
+            Ok(()) => Offered::Queued,
+            Err(_) => Offered::Gone,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    rooms: Mutex<HashMap<String, HashMap<u64, Queue>>>,
+    next_id: AtomicU64,
+}
+
+impl Inner {
+    fn remove(&self, id: u64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms.retain(|_, members| {
+            members.remove(&id);
+            !members.is_empty()
+        });
+    }
+}
+
+// fans messages out to the server connections in a room. A published message is
+// encoded once, every member gets the same unmasked frame.
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<Inner>,
+    //how many messages a member may have queued under SlowConsumer::Drop and Disconnect
+    queue_len: usize,
+}
+
+impl std::fmt::Debug for Hub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rooms = self.inner.rooms.lock().unwrap();
+        f.debug_struct("Hub")
+            .field(
+                "rooms",
+                &rooms
+                    .iter()
+                    .map(|(name, members)| (name, members.len()))
+                    .collect::<HashMap<_, _>>(),
+            )
+            .field("queue_len", &self.queue_len)
+            .finish()
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Hub {
+            inner: Arc::default(),
+            queue_len: 32,
+        }
+    }
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_queue_len(mut self, queue_len: usize) -> Self {
+        self.queue_len = queue_len;
+        self
+    }
+    // hands the writing side of a server connection to the hub, usually the writer half
+    // of `split`. It's written to from its own task until the Member is dropped.
+    pub fn add<S>(&self, client: Client<S>, policy: SlowConsumer) -> Result<Member, std::io::Error>
+    where
+        S: std::marker::Unpin + AsyncWrite + Send + 'static,
+    {
+        if client.role() != Role::Server {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "only server connections can join a hub, clients have to mask their frames",
+            ));
+        }
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = unbounded_channel();
+        let queue = Queue {
+            sender,
+            queued: Arc::default(),
+            overflowed: Arc::new(Notify::new()),
+            policy,
+            limit: match policy {
+                SlowConsumer::Buffer(limit) => limit,
+                _ => self.queue_len,
+            },
+        };
+        tokio::spawn(run_member(
+            client,
+            receiver,
+            queue.queued.clone(),
+            queue.overflowed.clone(),
+            policy,
+            id,
+            Arc::downgrade(&self.inner),
+        ));
+        Ok(Member {
+            id,
+            queue,
+            hub: self.inner.clone(),
+        })
+    }
+    // sends to everyone in the room, returns how many members it was queued for.
+    pub fn publish(&self, room: &str, message: &Message) -> usize {
+        let mut encoded = BytesMut::new();
+        message.write_into(&mut encoded, None);
+        self.publish_frame(room, &FrameBuf::from_bytes_unchecked(encoded.freeze()))
+    }
+    // like publish, for a frame that's already encoded. It has to be unmasked.
+    pub fn publish_frame(&self, room: &str, frame: &FrameBuf) -> usize {
+        assert!(!frame.has_mask(), "frames from a server must not be masked");
+        let mut gone = vec![];
+        let mut delivered = 0;
+        {
+            let rooms = self.inner.rooms.lock().unwrap();
+            for (id, queue) in rooms.get(room).into_iter().flatten() {
+                match queue.offer(frame) {
+                    Offered::Queued => delivered += 1,
+                    Offered::Dropped => {}
+                    Offered::Gone => gone.push(*id),
+                }
+            }
+        }
+        for id in gone {
+            self.inner.remove(id);
+        }
+        delivered
+    }
+    pub fn members(&self, room: &str) -> usize {
+        let rooms = self.inner.rooms.lock().unwrap();
+        rooms.get(room).map_or(0, HashMap::len)
+    }
+}
+
+// a connection in the hub. Dropping it leaves every room and ends the connection.
+pub struct Member {
+    id: u64,
+    queue: Queue,
+    hub: Arc<Inner>,
+}
+
+impl std::fmt::Debug for Member {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Member").field("id", &self.id).finish()
+    }
+}
+
+impl Member {
+    pub fn join(&self, room: &str) {
+        let mut rooms = self.hub.rooms.lock().unwrap();
+        rooms
+            .entry(room.to_owned())
+            .or_default()
+            .insert(self.id, self.queue.clone());
+    }
+    pub fn leave(&self, room: &str) {
+        let mut rooms = self.hub.rooms.lock().unwrap();
+        if let Some(members) = rooms.get_mut(room) {
+            members.remove(&self.id);
+            if members.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+    // sends to just this member, under the same slow consumer policy. Returns whether
+    // it was queued.
+    pub fn send(&self, message: &Message) -> bool {
+        let mut encoded = BytesMut::new();
+        message.write_into(&mut encoded, None);
+        match self
+            .queue
+            .offer(&FrameBuf::from_bytes_unchecked(encoded.freeze()))
+        {
+            Offered::Queued => true,
+            Offered::Dropped => false,
+            Offered::Gone => {
+                self.hub.remove(self.id);
+                false
+            }
+        }
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        self.hub.remove(self.id);
+    }
+}
+
+async fn run_member<S: std::marker::Unpin + AsyncWrite>(
+    mut client: Client<S>,
+    mut outgoing: UnboundedReceiver<FrameBuf>,
+    queued: Arc<AtomicUsize>,
+    overflowed: Arc<Notify>,
+    policy: SlowConsumer,
+    id: u64,
+    hub: Weak<Inner>,
+) {
+    //the frame being written and how much of it is out, a close has to wait for the rest
+    let mut in_flight: Option<(FrameBuf, usize)> = None;
+    let result = tokio::select! {
+        result = async {
+            loop {
+                if let Some((frame, written)) = &mut in_flight {
+                    client.write_encoded(frame.as_bytes(), written).await?;
+                    queued.fetch_sub(Queue::cost(policy, frame), Ordering::SeqCst);
+                    in_flight = None;
+                }
+                match poll_fn(|cx| client.poll_outgoing(cx, |cx| outgoing.poll_recv(cx))).await? {
+                    //a going-away close may have gone out in between
+                    Some(frame) => match client.check_sendable(frame.opcode())? {
+                        true => in_flight = Some((frame, 0)),
+                        false => {
+                            queued.fetch_sub(Queue::cost(policy, &frame), Ordering::SeqCst);
+                        }
+                    },
+                    None => return Ok::<_, std::io::Error>(()),
+                }
+            }
+        } => result,
+        _ = overflowed.notified() => {
+            //whatever it still had queued is thrown away, it only gets the close
+            drop(outgoing);
+            let _ = tokio::time::timeout(CLOSE_GRACE, async {
+                if let Some((frame, written)) = &mut in_flight {
+                    client.write_encoded(frame.as_bytes(), written).await?;
+                }
+                client.send_close(Some(SLOW_CONSUMER_CODE)).await?;
+                SinkExt::<Message>::close(&mut client).await
+            })
+            .await;
+            Ok(())
+        }
+    };
+    //a member that's gone doesn't need to be published to anymore
+    if result.is_err() {
+        if let Some(hub) = hub.upgrade() {
+            hub.remove(id);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// serving websockets from a hyper service, next to the rest of its routes:
+//
+// let (response, upgrading) = yaws::upgrade_hyper(request);
+// if let Some(upgrading) = upgrading {
+//     tokio::spawn(async move { handle(upgrading.await?) });
+// }
+// Ok(response)
+use crate::client::Client;
+use crate::frame::Role;
+use crate::server::{accept_key, check_upgrade, tokens, Accept, Refused};
+use ::hyper::header::{self, HeaderValue};
+use ::hyper::upgrade::{OnUpgrade, Upgraded};
+use ::hyper::{Body, Method, Request, Response, StatusCode, Version};
+use futures_util::{future::Future, ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// the connection hyper hands over once it has sent the 101 response.
+#[derive(Debug)]
+pub struct Upgrading {
+    on_upgrade: OnUpgrade,
+}
+
+impl Future for Upgrading {
+    type Output = Result<Client<Upgraded>, std::io::Error>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let upgraded =
+            ready!(Pin::new(&mut self.on_upgrade).poll(cx)).map_err(std::io::Error::other)?;
+        Poll::Ready(Ok(Client::from_upgraded(upgraded, Role::Server)))
+    }
+}
+
+fn refuse(status: StatusCode, reason: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(reason.to_owned()));
+    *response.status_mut() = status;
+    if status == StatusCode::UPGRADE_REQUIRED {
+        response.headers_mut().insert(
+            header::SEC_WEBSOCKET_VERSION,
+            HeaderValue::from_static("13"),
+        );
+    }
+    response
+}
+
+// accepts the upgrade without a subprotocol, see upgrade_hyper_with.
+pub fn upgrade_hyper(request: Request<Body>) -> (Response<Body>, Option<Upgrading>) {
+    upgrade_hyper_with(request, Accept::new())
+}
+
+// checks that the request is a websocket upgrade and answers it with a 101 carrying the
+// subprotocol and headers of `accept`. Return the response from the service, the
+// connection comes out of Upgrading after hyper has sent it. Requests that can't be
+// upgraded get the same 400, 426 or 500 the Router would send, and no Upgrading.
+pub fn upgrade_hyper_with(
+    request: Request<Body>,
+    accept: Accept,
+) -> (Response<Body>, Option<Upgrading>) {
+    match accept_upgrade(request, accept) {
+        Ok((response, upgrading)) => (response, Some(upgrading)),
+        Err(refused) => (refused, None),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn accept_upgrade(
+    request: Request<Body>,
+    accept: Accept,
+) -> Result<(Response<Body>, Upgrading), Response<Body>> {
+    if request.method() != Method::GET || request.version() != Version::HTTP_11 {
+        return Err(refuse(
+            StatusCode::BAD_REQUEST,
+            "websocket upgrades need a GET over HTTP/1.1",
+        ));
+    }
+    let key = match check_upgrade(request.headers()) {
+        Ok(key) => accept_key(key),
+        Err(Refused::Status(status, reason)) => return Err(refuse(status, reason)),
+        Err(Refused::Io(e)) => unreachable!("checking headers doesn't do io: {}", e),
+    };
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(
+        header::SEC_WEBSOCKET_ACCEPT,
+        HeaderValue::from_str(&key).expect("base64 is a valid header value"),
+    );
+    if let Some(protocol) = &accept.protocol {
+        if !tokens(request.headers(), "sec-websocket-protocol").any(|offered| offered == protocol) {
+            return Err(refuse(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "picked a subprotocol the client didn't offer",
+            ));
+        }
+        let protocol = HeaderValue::from_str(protocol).map_err(|_| {
+            refuse(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "picked a subprotocol that isn't a valid header value",
+            )
+        })?;
+        headers.insert(header::SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    for (name, value) in &accept.headers {
+        headers.append(name, value.clone());
+    }
+    let on_upgrade = request.into_body().on_upgrade();
+    Ok((response, Upgrading { on_upgrade }))
+}
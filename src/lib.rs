@@ -1,6 +1,45 @@
+mod admission;
 pub mod client;
+mod codec;
+mod connect;
+mod deflate;
 mod frame;
+mod handle;
+mod hub;
+#[cfg(feature = "hyper")]
+mod hyper_upgrade;
+pub mod mask;
+mod message;
+mod reconnect;
+mod resolve;
+mod server;
+mod shutdown;
+mod split;
+mod timeout;
+mod tls;
+mod utf8;
+mod validation;
+mod verify;
 pub use crate::client::Client;
-pub use client::{SecureClient, SecureReader, SecureWriter};
-pub use frame::Frame;
+pub use admission::{Admission, AdmissionStats, Limits};
+pub use client::{ReuniteError, SecureClient, SecureReader, SecureWriter};
+pub use codec::WsCodec;
+pub use connect::ConnectOptions;
+pub use deflate::{DeflateError, DeflateParams, DeflatePolicy};
 pub use frame::Opcode;
+pub use frame::Role;
+pub use frame::{Frame, FrameBuf, FrameBufError, WsParsingError};
+pub use handle::Sender;
+pub use hub::{Hub, Member, SlowConsumer};
+#[cfg(feature = "hyper")]
+pub use hyper_upgrade::{upgrade_hyper, upgrade_hyper_with, Upgrading};
+pub use message::Message;
+pub use reconnect::{Backoff, ConnectionEvent, ReconnectingClient};
+pub use resolve::{Resolver, SystemResolver};
+pub use server::{accept_key, Accept, Reject, Request, Router, Server};
+pub use shutdown::ShutdownHandle;
+pub use split::WriteHalf;
+pub use timeout::{Timeout, Timeouts};
+pub use tls::{load_certs, load_private_key, SecureServer, TlsServerConfig};
+pub use utf8::{InvalidUtf8, Utf8Validator};
+pub use validation::{Extensions, FrameError, LengthEncoding, TooBig};
@@ -0,0 +1,79 @@
+// (un)masking payloads. Masking is its own inverse, so the same routines are used
+// for outgoing and incoming frames. `mask` is the key as read from the frame header,
+// i.e. big endian, the same as Frame::mask returns.
+
+// picks the fastest implementation for the current cpu.
+pub fn apply_mask(data: &mut [u8], mask: u32) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            unsafe { apply_mask_avx2(data, mask) }
+        } else {
+            //sse2 is part of the x86_64 baseline, no need to check for it.
+            unsafe { apply_mask_sse2(data, mask) }
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    apply_mask_words(data, mask)
+}
+
+// one byte at a time, the reference implementation.
+pub fn apply_mask_scalar(data: &mut [u8], mask: u32) {
+    xor_bytes(data, mask.to_be_bytes());
+}
+
+fn xor_bytes(data: &mut [u8], mask_bytes: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask_bytes[i % 4];
+    }
+}
+
+// xors aligned 64 bit words, only the unaligned head and tail are done byte by byte.
+pub fn apply_mask_words(data: &mut [u8], mask: u32) {
+    let mask_bytes = mask.to_be_bytes();
+    let (head, words, tail) = unsafe { data.align_to_mut::<u64>() };
+    xor_bytes(head, mask_bytes);
+    //the words start head.len() bytes into the payload, line the key up with that.
+    let mut rotated = mask_bytes;
+    rotated.rotate_left(head.len() % 4);
+    let word = u64::from_ne_bytes([
+        rotated[0], rotated[1], rotated[2], rotated[3], rotated[0], rotated[1], rotated[2],
+        rotated[3],
+    ]);
+    for w in words.iter_mut() {
+        *w ^= word;
+    }
+    //words are a multiple of 4 bytes, so the tail has the same alignment to the key as they do.
+    xor_bytes(tail, rotated);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse2")]
+unsafe fn apply_mask_sse2(data: &mut [u8], mask: u32) {
+    use std::arch::x86_64::{
+        __m128i, _mm_loadu_si128, _mm_set1_epi32, _mm_storeu_si128, _mm_xor_si128,
+    };
+    let key = _mm_set1_epi32(i32::from_ne_bytes(mask.to_be_bytes()));
+    let mut chunks = data.chunks_exact_mut(16);
+    for chunk in &mut chunks {
+        let ptr = chunk.as_mut_ptr() as *mut __m128i;
+        _mm_storeu_si128(ptr, _mm_xor_si128(_mm_loadu_si128(ptr), key));
+    }
+    //chunks are a multiple of 4 bytes, so the key lines up with the remainder again.
+    apply_mask_words(chunks.into_remainder(), mask);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn apply_mask_avx2(data: &mut [u8], mask: u32) {
+    use std::arch::x86_64::{
+        __m256i, _mm256_loadu_si256, _mm256_set1_epi32, _mm256_storeu_si256, _mm256_xor_si256,
+    };
+    let key = _mm256_set1_epi32(i32::from_ne_bytes(mask.to_be_bytes()));
+    let mut chunks = data.chunks_exact_mut(32);
+    for chunk in &mut chunks {
+        let ptr = chunk.as_mut_ptr() as *mut __m256i;
+        _mm256_storeu_si256(ptr, _mm256_xor_si256(_mm256_loadu_si256(ptr), key));
+    }
+    apply_mask_sse2(chunks.into_remainder(), mask);
+}
@@ -0,0 +1,82 @@
+use crate::frame::{Frame, Opcode};
+use bytes::BytesMut;
+
+// a complete websocket message, with fragments already stitched together.
+// used where we can't hand out frames borrowed from the read buffer, like the
+// Stream and Sink implementations of Client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<u16>),
+}
+
+impl Message {
+    pub fn is_close(&self) -> bool {
+        matches!(self, Message::Close(_))
+    }
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Message::Text(_) => Opcode::Text,
+            Message::Binary(_) => Opcode::Binary,
+            Message::Ping(_) => Opcode::Ping,
+            Message::Pong(_) => Opcode::Pong,
+            Message::Close(_) => Opcode::Close,
+        }
+    }
+    // encodes the message as a single, final frame.
+    pub fn to_frame(&self, mask: Option<u32>) -> Box<Frame> {
+        match self {
+            Message::Text(text) => Frame::new_text(text, mask),
+            Message::Binary(data) => Frame::new_binary(data, mask, true),
+            Message::Ping(data) => Frame::new_ping(Some(data), mask),
+            Message::Pong(data) => Frame::new_pong(Some(data), mask),
+            Message::Close(code) => Frame::new_close(*code, mask),
+        }
+    }
+    // appends the message to `dst` as a single, final frame without allocating a Frame first.
+    pub fn write_into(&self, dst: &mut BytesMut, mask: Option<u32>) {
+        let close_code;
+        let payload: &[u8] = match self {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data,
+            Message::Close(code) => {
+                close_code = code.unwrap_or(1000).to_be_bytes();
+                &close_code
+            }
+        };
+        Frame::write_into_bytes(dst, self.opcode(), true, payload, mask);
+    }
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Message::Close(_) | Message::Ping(_) | Message::Pong(_)
+        )
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_owned())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(data: Vec<u8>) -> Self {
+        Message::Binary(data)
+    }
+}
+
+impl From<&[u8]> for Message {
+    fn from(data: &[u8]) -> Self {
+        Message::Binary(data.to_owned())
+    }
+}
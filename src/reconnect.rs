@@ -0,0 +1,286 @@
+use crate::client::Client;
+use crate::message::Message;
+use futures_util::{
+    future::{BoxFuture, Future, FutureExt},
+    sink::SinkExt,
+    stream::StreamExt,
+};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+
+// how long to wait between connection attempts. Starts at `initial` and grows by
+// `multiplier` up to `max`. Every delay is cut short by a random fraction of up to
+// `jitter`, so clients that dropped at the same time don't all redial at the same time.
+// A multiplier below 1 counts as 1 and jitter is kept between 0 and 1, NaN included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    // the delay after failed attempt number `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        //written so NaN falls through to the safe side of each comparison
+        let multiplier = if self.multiplier >= 1.0 {
+            self.multiplier
+        } else {
+            1.0
+        };
+        let jitter = if self.jitter > 0.0 {
+            self.jitter.min(1.0)
+        } else {
+            0.0
+        };
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let max = self.max.as_secs_f64();
+        let base = (self.initial.as_secs_f64() * multiplier.powi(exponent)).min(max);
+        let delay = base * (1.0 - rand::thread_rng().gen::<f64>() * jitter);
+        //max as f64 can round up past what a Duration holds
+        if delay < max {
+            Duration::from_secs_f64(delay)
+        } else {
+            self.max
+        }
+    }
+}
+
+// what a ReconnectingClient is up to, handed to the `on_event` callback.
+#[derive(Debug)]
+pub enum ConnectionEvent<'a> {
+    //`attempt` counts from 1 again after every successful connect
+    Connecting {
+        attempt: u32,
+    },
+    //connected and the on-connect hook went through
+    Connected,
+    //dialing or the hook failed. `retry_in` is None when this was the last attempt
+    ConnectFailed {
+        attempt: u32,
+        error: &'a std::io::Error,
+        retry_in: Option<Duration>,
+    },
+    //a live connection ended, with the error unless it was closed properly
+    Disconnected(Option<&'a std::io::Error>),
+    //ran out of attempts, the last error is returned to the caller
+    GaveUp,
+}
+
+type Connect<S> = Box<dyn FnMut() -> BoxFuture<'static, Result<Client<S>, std::io::Error>> + Send>;
+type OnConnect<S> =
+    Box<dyn for<'a> FnMut(&'a mut Client<S>) -> BoxFuture<'a, Result<(), std::io::Error>> + Send>;
+type OnEvent = Box<dyn for<'a> FnMut(&ConnectionEvent<'a>) + Send>;
+
+// a client that dials again whenever its connection drops. Nothing is connected
+// until the first `connect`, `recv` or `send`.
+pub struct ReconnectingClient<S> {
+    connect: Connect<S>,
+    //runs on every new connection before it's used, e.g. to authenticate or resubscribe
+    on_connect: Option<OnConnect<S>>,
+    on_event: Option<OnEvent>,
+    backoff: Backoff,
+    //None retries forever
+    max_attempts: Option<u32>,
+    client: Option<Client<S>>,
+}
+
+impl<S> std::fmt::Debug for ReconnectingClient<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReconnectingClient")
+            .field("backoff", &self.backoff)
+            .field("max_attempts", &self.max_attempts)
+            .field("connected", &self.client.is_some())
+            .finish()
+    }
+}
+
+impl ReconnectingClient<TlsStream<TcpStream>> {
+    pub fn secure<U: Into<String>>(uri: U) -> Self {
+        let uri = uri.into();
+        ReconnectingClient::new(move || Client::connect_secure(uri.clone()))
+    }
+}
+
+impl ReconnectingClient<TcpStream> {
+    pub fn insecure<U: Into<String>>(uri: U) -> Self {
+        let uri = uri.into();
+        ReconnectingClient::new(move || Client::connect_insecure(uri.clone()))
+    }
+}
+
+impl<S: Send + 'static> ReconnectingClient<S> {
+    // `connect` is called for every attempt and should do the whole handshake.
+    pub fn new<F, Fut>(mut connect: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Client<S>, std::io::Error>> + Send + 'static,
+    {
+        ReconnectingClient {
+            connect: Box::new(move || connect().boxed()),
+            on_connect: None,
+            on_event: None,
+            backoff: Backoff::default(),
+            max_attempts: None,
+            client: None,
+        }
+    }
+}
+
+impl<S> ReconnectingClient<S> {
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+    // gives up after this many attempts in a row, counting the first.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+    // an error from the hook counts as a failed attempt. e.g.
+    // `.on_connect(|client| Box::pin(client.send_str("subscribe")))`
+    pub fn on_connect<F>(mut self, hook: F) -> Self
+    where
+        F: for<'a> FnMut(&'a mut Client<S>) -> BoxFuture<'a, Result<(), std::io::Error>>
+            + Send
+            + 'static,
+    {
+        self.on_connect = Some(Box::new(hook));
+        self
+    }
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: for<'a> FnMut(&ConnectionEvent<'a>) + Send + 'static,
+    {
+        self.on_event = Some(Box::new(callback));
+        self
+    }
+    // the current connection, if there is one.
+    pub fn client(&mut self) -> Option<&mut Client<S>> {
+        self.client.as_mut()
+    }
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+    fn emit(&mut self, event: ConnectionEvent<'_>) {
+        if let Some(on_event) = &mut self.on_event {
+            on_event(&event);
+        }
+    }
+    fn disconnected(&mut self, error: Option<&std::io::Error>) {
+        self.client = None;
+        self.emit(ConnectionEvent::Disconnected(error));
+    }
+}
+
+impl<S: std::marker::Unpin + AsyncRead + AsyncWrite> ReconnectingClient<S> {
+    // returns the live connection, dialing until one is made or we run out of attempts.
+    pub async fn connect(&mut self) -> Result<&mut Client<S>, std::io::Error> {
+        if self.client.is_none() {
+            let client = self.dial().await?;
+            self.client = Some(client);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+    async fn dial(&mut self) -> Result<Client<S>, std::io::Error> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.emit(ConnectionEvent::Connecting { attempt });
+            let result = match (self.connect)().await {
+                Ok(mut client) => match &mut self.on_connect {
+                    Some(hook) => hook(&mut client).await.map(|_| client),
+                    None => Ok(client),
+                },
+                Err(e) => Err(e),
+            };
+            let error = match result {
+                Ok(client) => {
+                    self.emit(ConnectionEvent::Connected);
+                    return Ok(client);
+                }
+                Err(e) => e,
+            };
+            let retry_in = match self.max_attempts {
+                Some(max_attempts) if attempt >= max_attempts => None,
+                _ => Some(self.backoff.delay(attempt)),
+            };
+            self.emit(ConnectionEvent::ConnectFailed {
+                attempt,
+                error: &error,
+                retry_in,
+            });
+            match retry_in {
+                Some(delay) => tokio::time::delay_for(delay).await,
+                None => {
+                    self.emit(ConnectionEvent::GaveUp);
+                    return Err(error);
+                }
+            }
+        }
+    }
+    // the next message, reconnecting whenever the connection drops. Pings are answered
+    // here. A close from the server is answered and handed back, the call after that
+    // reconnects. Only fails once we give up on reconnecting.
+    pub async fn recv(&mut self) -> Result<Message, std::io::Error> {
+        loop {
+            let client = self.connect().await?;
+            let error = match client.next().await {
+                Some(Ok(message)) => {
+                    let reply = match &message {
+                        Message::Ping(data) => Some(Message::Pong(data.clone())),
+                        Message::Close(code) => Some(Message::Close(*code)),
+                        _ => None,
+                    };
+                    if let Some(reply) = reply {
+                        //if this fails the next read finds out the connection is gone
+                        let _ = client.send(reply).await;
+                    }
+                    if message.is_close() {
+                        self.disconnected(None);
+                    }
+                    return Ok(message);
+                }
+                Some(Err(e)) => Some(e),
+                None => None,
+            };
+            self.disconnected(error.as_ref());
+        }
+    }
+    // sends on the live connection, dialing first if there is none. A message that
+    // fails to send is not retried, it may have been partially written.
+    pub async fn send<M: Into<Message>>(&mut self, message: M) -> Result<(), std::io::Error> {
+        let client = self.connect().await?;
+        if let Err(e) = client.send(message.into()).await {
+            self.disconnected(Some(&e));
+            return Err(e);
+        }
+        Ok(())
+    }
+    // closes the current connection, if any. Using the client after this connects again.
+    pub async fn close(&mut self, code: Option<u16>) -> Result<(), std::io::Error> {
+        match self.client.take() {
+            Some(mut client) => {
+                let result = client.close(code).await;
+                self.emit(ConnectionEvent::Disconnected(result.as_ref().err()));
+                result
+            }
+            None => Ok(()),
+        }
+    }
+}
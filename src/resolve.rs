@@ -0,0 +1,126 @@
+use futures_util::{
+    future::BoxFuture,
+    stream::{FuturesUnordered, StreamExt},
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+// turns a host name into addresses to dial. Swap it out in ConnectOptions to use
+// your own dns, or to point names at test servers.
+pub trait Resolver: std::fmt::Debug + Send + Sync {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<Vec<SocketAddr>, std::io::Error>>;
+}
+
+// whatever the os resolves the name to, through tokio's lookup_host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<Vec<SocketAddr>, std::io::Error>> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+// RFC 8305 6: alternate between families, starting with the family of the first address.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = addrs.first().is_none_or(SocketAddr::is_ipv6);
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == prefer_v6);
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+// happy eyeballs (RFC 8305): starts the next attempt whenever the previous one failed or
+// has been going for `attempt_delay`, the first connection to come up wins.
+pub(crate) async fn connect_any(
+    addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+) -> Result<TcpStream, std::io::Error> {
+    let mut addrs = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    attempts.extend(addrs.next().map(TcpStream::connect));
+    loop {
+        let result = if addrs.len() == 0 {
+            attempts.next().await
+        } else {
+            match tokio::time::timeout(attempt_delay, attempts.next()).await {
+                Ok(result) => result,
+                Err(_) => {
+                    attempts.extend(addrs.next().map(TcpStream::connect));
+                    continue;
+                }
+            }
+        };
+        match result {
+            Some(Ok(stream)) => return Ok(stream),
+            Some(Err(e)) => {
+                last_error = Some(e);
+                attempts.extend(addrs.next().map(TcpStream::connect));
+            }
+            None => {
+                return Err(last_error.unwrap_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        "host did not resolve to any addresses",
+                    )
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn interleaves_starting_with_the_first_family() {
+        let v6_first = addrs(&[
+            "[::1]:80",
+            "[::2]:80",
+            "[::3]:80",
+            "1.0.0.1:80",
+            "1.0.0.2:80",
+        ]);
+        assert_eq!(
+            interleave(v6_first),
+            addrs(&[
+                "[::1]:80",
+                "1.0.0.1:80",
+                "[::2]:80",
+                "1.0.0.2:80",
+                "[::3]:80"
+            ])
+        );
+        let v4_first = addrs(&["1.0.0.1:80", "[::1]:80", "[::2]:80"]);
+        assert_eq!(
+            interleave(v4_first),
+            addrs(&["1.0.0.1:80", "[::1]:80", "[::2]:80"])
+        );
+        //order within a family is kept
+        let one_family = addrs(&["1.0.0.3:80", "1.0.0.1:80", "1.0.0.2:80"]);
+        assert_eq!(interleave(one_family.clone()), one_family);
+        assert!(interleave(vec![]).is_empty());
+    }
+}
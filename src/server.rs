@@ -0,0 +1,627 @@
+use crate::admission::{Admission, Limits, Refusal};
+use crate::client::Client;
+use crate::deflate::{self, DeflatePolicy};
+use crate::frame::Role;
+use crate::shutdown::{Shutdown, ShutdownHandle};
+use crate::timeout::{Timeout, Timeouts};
+use futures_util::future::{BoxFuture, Future, FutureExt};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use http::{StatusCode, Uri};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::watch;
+
+// upgrade requests with more header bytes than this are turned away.
+const MAX_HEADER_SIZE: u64 = 16 << 10;
+// how long a connection that's over the limits gets to take its 503 or 429.
+pub(crate) const REFUSE_DEADLINE: Duration = Duration::from_secs(1);
+
+// RFC 6455 4.2.2: the Sec-WebSocket-Accept for the Sec-WebSocket-Key a client sent.
+pub fn accept_key(key: &str) -> String {
+    let mut sha = Sha1::new();
+    sha.update(key.as_bytes());
+    sha.update(b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    base64::encode(sha.finalize())
+}
+
+// the upgrade request a connection was accepted with.
+#[derive(Debug, Clone)]
+pub struct Request {
+    uri: Uri,
+    headers: HeaderMap,
+    //the {name} segments of the route that matched
+    params: HashMap<String, String>,
+    //the subprotocol the connection was accepted with
+    protocol: Option<String>,
+}
+
+impl Request {
+    pub fn uri(&self) -> &Uri {
+        &self.uri
+    }
+    pub fn path(&self) -> &str {
+        self.uri.path()
+    }
+    pub fn query(&self) -> Option<&str> {
+        self.uri.query()
+    }
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    // the header as a string, None if it's missing or not valid ascii.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+    // e.g. `id` for a route like `/feed/{id}`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+    pub fn origin(&self) -> Option<&str> {
+        self.header("origin")
+    }
+    // the subprotocols the client offered, in its order of preference.
+    pub fn protocols(&self) -> Vec<&str> {
+        tokens(&self.headers, "sec-websocket-protocol").collect()
+    }
+    // the subprotocol picked by the authorize hook, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+}
+
+// what the authorize hook says to go ahead with the upgrade.
+#[derive(Debug, Clone, Default)]
+pub struct Accept {
+    pub(crate) protocol: Option<String>,
+    pub(crate) headers: HeaderMap,
+}
+
+impl Accept {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // has to be one of Request::protocols, the upgrade fails with a 500 otherwise.
+    pub fn with_protocol<P: Into<String>>(mut self, protocol: P) -> Self {
+        self.protocol = Some(protocol.into());
+        self
+    }
+    // an extra header for the 101 response, e.g. Set-Cookie.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+}
+
+// what the authorize hook answers instead of upgrading.
+#[derive(Debug, Clone)]
+pub struct Reject {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: String,
+}
+
+impl Reject {
+    pub fn new(status: StatusCode) -> Self {
+        Reject {
+            status,
+            headers: HeaderMap::new(),
+            body: String::new(),
+        }
+    }
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+    pub fn with_body<B: Into<String>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+}
+
+// why a connection wasn't upgraded.
+pub(crate) enum Refused {
+    Io(std::io::Error),
+    Status(StatusCode, &'static str),
+}
+
+impl From<std::io::Error> for Refused {
+    fn from(e: std::io::Error) -> Self {
+        Refused::Io(e)
+    }
+}
+
+// reads the request line and headers, up to and including the empty line.
+async fn read_request<R: std::marker::Unpin + AsyncRead>(
+    buffered: &mut BufReader<R>,
+) -> Result<(Uri, HeaderMap), Refused> {
+    let mut head = buffered.take(MAX_HEADER_SIZE);
+    let mut line = String::new();
+    let too_large = Refused::Status(
+        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+        "request headers are too large",
+    );
+    let bad_request = |reason| Refused::Status(StatusCode::BAD_REQUEST, reason);
+    if head.read_line(&mut line).await? == 0 {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    if !line.ends_with('\n') {
+        return Err(too_large);
+    }
+    let mut parts = line.trim_end().split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => return Err(bad_request("malformed request line")),
+    };
+    if method != "GET" || version != "HTTP/1.1" {
+        return Err(bad_request("websocket upgrades need a GET over HTTP/1.1"));
+    }
+    let uri: Uri = target
+        .parse()
+        .map_err(|_| bad_request("malformed request target"))?;
+    let mut headers = HeaderMap::new();
+    loop {
+        line.clear();
+        if head.read_line(&mut line).await? == 0 {
+            return Err(match head.limit() {
+                0 => too_large,
+                _ => std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into(),
+            });
+        }
+        if !line.ends_with('\n') {
+            return Err(too_large);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok((uri, headers));
+        }
+        let mut split = line.splitn(2, ':');
+        let (name, value) = match (split.next(), split.next()) {
+            (Some(name), Some(value)) => (name, value.trim()),
+            _ => return Err(bad_request("malformed header")),
+        };
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| bad_request("malformed header name"))?;
+        let value =
+            HeaderValue::from_str(value).map_err(|_| bad_request("malformed header value"))?;
+        headers.append(name, value);
+    }
+}
+
+// read_request, failing with Timeout::Upgrade when the client takes longer than `limit`.
+async fn read_request_within<R: std::marker::Unpin + AsyncRead>(
+    buffered: &mut BufReader<R>,
+    limit: Option<Duration>,
+) -> Result<(Uri, HeaderMap), Refused> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, read_request(buffered))
+            .await
+            .unwrap_or_else(|_| Err(std::io::Error::from(Timeout::Upgrade).into())),
+        None => read_request(buffered).await,
+    }
+}
+
+// the values of a comma separated header like Connection, over all its occurrences.
+pub(crate) fn tokens<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+fn has_token(headers: &HeaderMap, name: &str, token: &str) -> bool {
+    tokens(headers, name).any(|value| value.eq_ignore_ascii_case(token))
+}
+
+// RFC 6455 4.2.1, returns the Sec-WebSocket-Key if this is a valid upgrade request.
+pub(crate) fn check_upgrade(headers: &HeaderMap) -> Result<&str, Refused> {
+    let bad_request = |reason| Refused::Status(StatusCode::BAD_REQUEST, reason);
+    if !has_token(headers, "upgrade", "websocket") {
+        return Err(bad_request("expected a websocket upgrade"));
+    }
+    if !has_token(headers, "connection", "upgrade") {
+        return Err(bad_request("expected Connection: Upgrade"));
+    }
+    if !has_token(headers, "sec-websocket-version", "13") {
+        return Err(Refused::Status(
+            StatusCode::UPGRADE_REQUIRED,
+            "only websocket version 13 is supported",
+        ));
+    }
+    let key = headers
+        .get("sec-websocket-key")
+        .and_then(|key| key.to_str().ok())
+        .ok_or_else(|| bad_request("missing Sec-WebSocket-Key"))?;
+    match base64::decode(key) {
+        Ok(nonce) if nonce.len() == 16 => Ok(key),
+        _ => Err(bad_request("invalid Sec-WebSocket-Key")),
+    }
+}
+
+fn write_headers(response: &mut Vec<u8>, headers: &HeaderMap) {
+    for (name, value) in headers {
+        response.extend_from_slice(name.as_str().as_bytes());
+        response.extend_from_slice(b": ");
+        response.extend_from_slice(value.as_bytes());
+        response.extend_from_slice(b"\r\n");
+    }
+}
+
+// answers with a plain http response and closes the connection.
+async fn respond<S: std::marker::Unpin + AsyncWrite>(
+    stream: &mut S,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &str,
+) -> Result<(), std::io::Error> {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or(""),
+        body.len()
+    )
+    .into_bytes();
+    if status == StatusCode::UPGRADE_REQUIRED {
+        response.extend_from_slice(b"Sec-WebSocket-Version: 13\r\n");
+    }
+    write_headers(&mut response, headers);
+    response.extend_from_slice(b"\r\n");
+    response.extend_from_slice(body.as_bytes());
+    stream.write_all(&response).await?;
+    stream.shutdown().await
+}
+
+// turns away a connection that's over the server's limits, without waiting for its request.
+// What it sends is read afterwards, closing on unread data could reset the connection before
+// the client sees the response. All of that gets REFUSE_DEADLINE.
+pub(crate) async fn refuse<S: std::marker::Unpin + AsyncRead + AsyncWrite>(
+    mut stream: S,
+    refusal: Refusal,
+) -> Result<(), std::io::Error> {
+    //Retry-After is in whole seconds, round up so clients don't come back too early
+    let retry_after =
+        refusal.retry_after.as_secs() + u64::from(refusal.retry_after.subsec_nanos() > 0);
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        http::header::RETRY_AFTER,
+        HeaderValue::from(retry_after.max(1)),
+    );
+    let refused = async {
+        respond(&mut stream, refusal.status, &headers, refusal.reason).await?;
+        //until the client closes its end, or has sent more than a request would be
+        let mut request = (&mut stream).take(MAX_HEADER_SIZE);
+        tokio::io::copy(&mut request, &mut tokio::io::sink()).await?;
+        Ok(())
+    };
+    tokio::time::timeout(REFUSE_DEADLINE, refused)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+// a route pattern, `/feed/{id}` is [Literal(""), Literal("feed"), Param("id")].
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern
+        .split('/')
+        .map(|segment| {
+            match segment
+                .strip_prefix('{')
+                .and_then(|name| name.strip_suffix('}'))
+            {
+                Some(name) => Segment::Param(name.to_owned()),
+                None => Segment::Literal(segment.to_owned()),
+            }
+        })
+        .collect()
+}
+
+fn match_pattern(pattern: &[Segment], path: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    let mut segments = path.split('/');
+    for expected in pattern {
+        match (expected, segments.next()) {
+            (Segment::Literal(literal), Some(segment)) if literal == segment => {}
+            (Segment::Param(name), Some(segment)) if !segment.is_empty() => {
+                params.insert(name.clone(), segment.to_owned());
+            }
+            _ => return None,
+        }
+    }
+    match segments.next() {
+        None => Some(params),
+        Some(_) => None,
+    }
+}
+
+type Handler<S> = Arc<dyn Fn(Client<S>, Request) -> BoxFuture<'static, ()> + Send + Sync>;
+type Authorize =
+    Box<dyn for<'a> Fn(&'a Request) -> BoxFuture<'a, Result<Accept, Reject>> + Send + Sync>;
+
+// picks a handler by the path of the upgrade request. Routes are tried in the order
+// they were added, `{name}` matches any one non-empty segment.
+pub struct Router<S> {
+    routes: Vec<(Vec<Segment>, Handler<S>)>,
+    //decides about every valid upgrade request before it's accepted
+    authorize: Option<Authorize>,
+    //accepts permessage-deflate offers that fit it, when set
+    deflate: Option<DeflatePolicy>,
+}
+
+impl<S> std::fmt::Debug for Router<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Router")
+            .field(
+                "routes",
+                &self
+                    .routes
+                    .iter()
+                    .map(|(pattern, _)| pattern)
+                    .collect::<Vec<_>>(),
+            )
+            .field("authorize", &self.authorize.is_some())
+            .field("deflate", &self.deflate)
+            .finish()
+    }
+}
+
+impl<S> Default for Router<S> {
+    fn default() -> Self {
+        Router {
+            routes: vec![],
+            authorize: None,
+            deflate: None,
+        }
+    }
+}
+
+impl<S> Router<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn route<F, Fut>(mut self, pattern: &str, handler: F) -> Self
+    where
+        F: Fn(Client<S>, Request) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.routes.push((
+            parse_pattern(pattern),
+            Arc::new(move |client, request| handler(client, request).boxed()),
+        ));
+        self
+    }
+    // runs for every valid upgrade request to a known path, e.g. to check the Origin
+    // or a session cookie. Without one every request is accepted.
+    // `.authorize(|request| Box::pin(async move { ... }))`
+    pub fn authorize<F>(mut self, hook: F) -> Self
+    where
+        F: for<'a> Fn(&'a Request) -> BoxFuture<'a, Result<Accept, Reject>> + Send + Sync + 'static,
+    {
+        self.authorize = Some(Box::new(hook));
+        self
+    }
+    // compresses messages on connections whose client offers permessage-deflate in a way
+    // the policy agrees with. Without it offers are ignored.
+    pub fn permessage_deflate(mut self, policy: DeflatePolicy) -> Self {
+        self.deflate = Some(policy);
+        self
+    }
+    fn find(&self, path: &str) -> Option<(&Handler<S>, HashMap<String, String>)> {
+        self.routes
+            .iter()
+            .find_map(|(pattern, handler)| Some((handler, match_pattern(pattern, path)?)))
+    }
+}
+
+impl<S: std::marker::Unpin + AsyncRead + AsyncWrite> Router<S> {
+    // takes one connection through the handshake and runs the handler it's routed to.
+    // requests for unknown paths get a 404, anything that isn't a valid upgrade a 400.
+    pub async fn serve(&self, stream: S) -> Result<(), std::io::Error> {
+        self.serve_with(stream, None, Timeouts::default()).await
+    }
+    // like serve, for a connection of a server that can shut down. Upgrades that come in
+    // after it started get a 503.
+    pub(crate) async fn serve_with(
+        &self,
+        stream: S,
+        going_away: Option<watch::Receiver<Option<String>>>,
+        timeouts: Timeouts,
+    ) -> Result<(), std::io::Error> {
+        let mut buffered = BufReader::new(stream);
+        let result = match read_request_within(&mut buffered, timeouts.upgrade).await {
+            Ok((uri, headers)) => match self.find(uri.path()) {
+                Some((handler, params)) => check_upgrade(&headers)
+                    .map(accept_key)
+                    .map(|accept| (handler.clone(), accept, uri, headers, params)),
+                None => Err(Refused::Status(StatusCode::NOT_FOUND, "no such path")),
+            },
+            Err(refused) => Err(refused),
+        };
+        let (handler, accept, uri, headers, params) = match result {
+            Ok(accepted) => accepted,
+            Err(Refused::Io(e)) => return Err(e),
+            Err(Refused::Status(status, body)) => {
+                return respond(buffered.get_mut(), status, &HeaderMap::new(), body).await
+            }
+        };
+        let mut request = Request {
+            uri,
+            headers,
+            params,
+            protocol: None,
+        };
+        let verdict = match &self.authorize {
+            Some(authorize) => authorize(&request).await,
+            None => Ok(Accept::new()),
+        };
+        let offered = |protocol: &str| request.protocols().contains(&protocol);
+        let verdict = match verdict {
+            Ok(accepted) if !accepted.protocol.as_deref().is_none_or(offered) => {
+                Err(Reject::new(StatusCode::INTERNAL_SERVER_ERROR)
+                    .with_body("picked a subprotocol the client didn't offer"))
+            }
+            verdict => verdict,
+        };
+        let shutting_down = going_away
+            .as_ref()
+            .is_some_and(|signal| signal.borrow().is_some());
+        let verdict =
+            match verdict {
+                Ok(_) if shutting_down => Err(Reject::new(StatusCode::SERVICE_UNAVAILABLE)
+                    .with_body("server is shutting down")),
+                verdict => verdict,
+            };
+        let accepted = match verdict {
+            Ok(accepted) => accepted,
+            Err(rejected) => {
+                let stream = buffered.get_mut();
+                return respond(stream, rejected.status, &rejected.headers, &rejected.body).await;
+            }
+        };
+        let mut response = format!(
+            "HTTP/1.1 101 Switching Protocols\r
+Upgrade: websocket\r
+Connection: Upgrade\r
+Sec-WebSocket-Accept: {}\r\n",
+            accept
+        )
+        .into_bytes();
+        if let Some(protocol) = &accepted.protocol {
+            response
+                .extend_from_slice(format!("Sec-WebSocket-Protocol: {}\r\n", protocol).as_bytes());
+        }
+        let deflate = self
+            .deflate
+            .as_ref()
+            .and_then(|policy| Some((deflate::negotiate(&request.headers, policy)?, policy)));
+        if let Some((params, _)) = &deflate {
+            response
+                .extend_from_slice(format!("Sec-WebSocket-Extensions: {}\r\n", params).as_bytes());
+        }
+        write_headers(&mut response, &accepted.headers);
+        response.extend_from_slice(b"\r\n");
+        buffered.get_mut().write_all(&response).await?;
+        request.protocol = accepted.protocol;
+        //the client may have sent frames right after the request, keep them.
+        let mut vec = buffered.buffer().to_owned();
+        let read_buffer_head = vec.len();
+        vec.resize(read_buffer_head.max(4096), 0);
+        let mut client =
+            Client::from_stream(buffered.into_inner(), Role::Server, vec, read_buffer_head);
+        client.set_timeouts(timeouts);
+        if let Some(signal) = going_away {
+            client.set_going_away(signal);
+        }
+        if let Some((params, policy)) = deflate {
+            client.set_deflate(params, policy);
+        }
+        handler(client, request).await;
+        Ok(())
+    }
+}
+
+// waits for the next connection. Failing to accept one isn't a reason to stop: it was
+// reset before we got to it, or we're out of file descriptors for now. The latter
+// would fail again right away, so it's given a moment for connections to close first.
+pub(crate) async fn accept(listener: &mut TcpListener) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                log::warn!("failed to accept a connection: {}", e);
+                if !matches!(
+                    e.kind(),
+                    std::io::ErrorKind::ConnectionAborted
+                        | std::io::ErrorKind::ConnectionReset
+                        | std::io::ErrorKind::Interrupted
+                ) {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+}
+
+// accepts tcp connections and serves each one from its own task.
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    router: Arc<Router<TcpStream>>,
+    shutdown: Arc<Shutdown>,
+    admission: Admission,
+    timeouts: Timeouts,
+}
+
+impl Server {
+    pub async fn bind<A: ToSocketAddrs>(
+        addr: A,
+        router: Router<TcpStream>,
+    ) -> Result<Self, std::io::Error> {
+        Ok(Server {
+            listener: TcpListener::bind(addr).await?,
+            router: Arc::new(router),
+            shutdown: Shutdown::new(),
+            admission: Admission::default(),
+            timeouts: Timeouts::for_server(),
+        })
+    }
+    pub fn with_limits(self, limits: Limits) -> Self {
+        self.admission.set_limits(limits);
+        self
+    }
+    // Timeouts::for_server unless set. The upgrade timeout bounds reading the request, the
+    // idle ones are handed to every client.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+    // the server's limits, to change them or see what they've turned away.
+    pub fn admission(&self) -> Admission {
+        self.admission.clone()
+    }
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr, std::io::Error> {
+        self.listener.local_addr()
+    }
+    // grab this before `run`, it's how the server gets stopped.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.shutdown.clone())
+    }
+    // returns with Ok once a shutdown has started. Errors on single connections are
+    // dropped, failed accepts are logged.
+    pub async fn run(mut self) -> Result<(), std::io::Error> {
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = accept(&mut self.listener) => accepted,
+                _ = self.shutdown.going_away() => return Ok(()),
+            };
+            let admitted = match self.admission.admit(peer.ip()) {
+                Ok(admitted) => Ok(admitted),
+                //too many are being refused already, this one is just closed
+                Err(refusal) => match self.admission.refusing() {
+                    Some(refusing) => Err((refusal, refusing)),
+                    None => continue,
+                },
+            };
+            let router = self.router.clone();
+            let signal = self.shutdown.going_away_signal();
+            let timeouts = self.timeouts;
+            tokio::spawn(self.shutdown.track(async move {
+                let _ = match admitted {
+                    Ok(_admitted) => router.serve_with(stream, Some(signal), timeouts).await,
+                    Err((refusal, _refusing)) => refuse(stream, refusal).await,
+                };
+            }));
+        }
+    }
+}
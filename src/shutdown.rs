@@ -0,0 +1,111 @@
+use futures_util::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+// shared by a server, the tasks serving its connections and its ShutdownHandles.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    //the reason connections are told when the server goes away, None while it's running
+    going_away: watch::Sender<Option<String>>,
+    going_away_signal: watch::Receiver<Option<String>>,
+    //set once the deadline has passed, connections still open are dropped
+    dropped: watch::Sender<bool>,
+    dropped_signal: watch::Receiver<bool>,
+    open: AtomicUsize,
+    //notified when the last open connection is done
+    drained: Notify,
+}
+
+// decrements the open count when a connection's task ends, however it ends.
+struct Open(Arc<Shutdown>);
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        if self.0.open.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify();
+        }
+    }
+}
+
+impl Shutdown {
+    pub(crate) fn new() -> Arc<Self> {
+        let (going_away, going_away_signal) = watch::channel(None);
+        let (dropped, dropped_signal) = watch::channel(false);
+        Arc::new(Shutdown {
+            going_away,
+            going_away_signal,
+            dropped,
+            dropped_signal,
+            open: AtomicUsize::new(0),
+            drained: Notify::new(),
+        })
+    }
+    pub(crate) fn going_away_signal(&self) -> watch::Receiver<Option<String>> {
+        self.going_away_signal.clone()
+    }
+    pub(crate) fn is_going_away(&self) -> bool {
+        self.going_away_signal.borrow().is_some()
+    }
+    // resolves once the server starts shutting down, to stop accepting.
+    pub(crate) async fn going_away(&self) {
+        let mut signal = self.going_away_signal();
+        while let Some(None) = signal.recv().await {}
+    }
+    // runs one connection, counted as open from now until it's done or dropped at the deadline.
+    pub(crate) fn track<F: Future<Output = ()>>(
+        self: &Arc<Self>,
+        connection: F,
+    ) -> impl Future<Output = ()> {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        let open = Open(self.clone());
+        let mut dropped = self.dropped_signal.clone();
+        async move {
+            let _open = open;
+            tokio::select! {
+                _ = connection => {},
+                _ = async move { while let Some(false) = dropped.recv().await {} } => {},
+            }
+        }
+    }
+    async fn drained(&self) {
+        while self.open.load(Ordering::SeqCst) > 0 {
+            self.drained.notified().await;
+        }
+    }
+}
+
+// shuts a Server or SecureServer down from outside, e.g. on a signal during a deploy.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    shutdown: Arc<Shutdown>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(shutdown: Arc<Shutdown>) -> Self {
+        ShutdownHandle { shutdown }
+    }
+    // stops accepting and sends every open connection a close with 1001 (Going Away) and
+    // `reason`. Waits up to `deadline` for the close handshakes, then drops whatever is left.
+    // Returns how many connections had to be dropped.
+    pub async fn shutdown(&self, reason: &str, deadline: Duration) -> usize {
+        let _ = self.shutdown.going_away.broadcast(Some(reason.to_owned()));
+        if tokio::time::timeout(deadline, self.shutdown.drained())
+            .await
+            .is_ok()
+        {
+            return 0;
+        }
+        let dropped = self.open_connections();
+        let _ = self.shutdown.dropped.broadcast(true);
+        self.shutdown.drained().await;
+        dropped
+    }
+    pub fn open_connections(&self) -> usize {
+        self.shutdown.open.load(Ordering::SeqCst)
+    }
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_going_away()
+    }
+}
@@ -0,0 +1,201 @@
+use bytes::{Buf, BytesMut};
+use futures_util::ready;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncWrite, ReadHalf};
+
+// follows the frames written through a WriteHalf, so replies only ever go in between them.
+#[derive(Debug, Default)]
+struct Frames {
+    //the header of the frame being written, until it's complete
+    header: [u8; 14],
+    header_len: usize,
+    //payload bytes left in the frame being written
+    remaining: u64,
+}
+
+impl Frames {
+    fn at_boundary(&self) -> bool {
+        self.header_len == 0 && self.remaining == 0
+    }
+    fn header_size(&self) -> Option<usize> {
+        if self.header_len < 2 {
+            return None;
+        }
+        let mask = if self.header[1] & 0x80 != 0 { 4 } else { 0 };
+        Some(match self.header[1] & 0x7F {
+            126 => 4 + mask,
+            127 => 10 + mask,
+            _ => 2 + mask,
+        })
+    }
+    fn written(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let skipped = (bytes.len() as u64).min(self.remaining);
+                self.remaining -= skipped;
+                bytes = &bytes[skipped as usize..];
+                continue;
+            }
+            let wanted = self.header_size().unwrap_or(2) - self.header_len;
+            let taken = wanted.min(bytes.len());
+            self.header[self.header_len..self.header_len + taken].copy_from_slice(&bytes[..taken]);
+            self.header_len += taken;
+            bytes = &bytes[taken..];
+            if self.header_size() == Some(self.header_len) {
+                let len = &self.header[2..self.header_len];
+                self.remaining = match self.header[1] & 0x7F {
+                    126 => u16::from_be_bytes([len[0], len[1]]) as u64,
+                    127 => u64::from_be_bytes([
+                        len[0], len[1], len[2], len[3], len[4], len[5], len[6], len[7],
+                    ]),
+                    len => len as u64,
+                };
+                self.header_len = 0;
+            }
+        }
+    }
+}
+
+pub(crate) struct Shared<S> {
+    stream: tokio::io::WriteHalf<S>,
+    //encoded pongs and close replies from the reader half, waiting for a frame boundary
+    replies: BytesMut,
+    frames: Frames,
+    //the reader, waiting for the writer to finish a frame so its replies can go out
+    reader: Option<Waker>,
+}
+
+impl<S> std::fmt::Debug for Shared<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Shared")
+            .field("replies", &self.replies.len())
+            .field("frames", &self.frames)
+            .finish()
+    }
+}
+
+impl<S: AsyncWrite> Shared<S> {
+    // only call this at a frame boundary, once started the replies have to be written in full.
+    fn poll_replies(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        while !self.replies.is_empty() {
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.replies))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.replies.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+    fn poll_replies_first(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        if !self.replies.is_empty() && self.frames.at_boundary() {
+            ready!(self.poll_replies(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+// how the reader half of a split client gets its replies out without waiting on the writer.
+pub(crate) trait Replies: std::fmt::Debug + Send + Sync {
+    fn queue(&self, reply: &[u8]);
+    // writes out queued replies, unless the writer is partway through a frame. Then they go
+    // out as soon as it's done with it.
+    fn poll_send(&self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>>;
+}
+
+impl<S: AsyncWrite + Send> Replies for Mutex<Shared<S>> {
+    fn queue(&self, reply: &[u8]) {
+        self.lock().unwrap().replies.extend_from_slice(reply);
+    }
+    fn poll_send(&self, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let mut shared = self.lock().unwrap();
+        if shared.replies.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        if !shared.frames.at_boundary() {
+            shared.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        ready!(shared.poll_replies(cx))?;
+        Pin::new(&mut shared.stream).poll_flush(cx)
+    }
+}
+
+// the writing side of a split client. The reader half shares it to answer pings and
+// close frames by itself, in between the frames written here.
+pub struct WriteHalf<S> {
+    shared: Arc<Mutex<Shared<S>>>,
+}
+
+impl<S> std::fmt::Debug for WriteHalf<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("WriteHalf(..)")
+    }
+}
+
+impl<S: AsyncWrite + Send + 'static> WriteHalf<S> {
+    pub(crate) fn new(stream: tokio::io::WriteHalf<S>) -> (Self, Arc<dyn Replies>) {
+        let shared = Arc::new(Mutex::new(Shared {
+            stream,
+            replies: BytesMut::new(),
+            frames: Frames::default(),
+            reader: None,
+        }));
+        (
+            WriteHalf {
+                shared: shared.clone(),
+            },
+            shared,
+        )
+    }
+}
+
+impl<S> WriteHalf<S> {
+    pub(crate) fn is_pair_of(&self, reader: &ReadHalf<S>) -> bool {
+        reader.is_pair_of(&self.shared.lock().unwrap().stream)
+    }
+    // the stream back, with replies that haven't gone out yet. Fails while the reader's
+    // handle on it is still around.
+    pub(crate) fn into_inner(self) -> Result<(tokio::io::WriteHalf<S>, BytesMut), Self> {
+        match Arc::try_unwrap(self.shared) {
+            Ok(shared) => {
+                let shared = shared.into_inner().unwrap();
+                Ok((shared.stream, shared.replies))
+            }
+            Err(shared) => Err(WriteHalf { shared }),
+        }
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for WriteHalf<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        let mut shared = self.shared.lock().unwrap();
+        ready!(shared.poll_replies_first(cx))?;
+        let written = ready!(Pin::new(&mut shared.stream).poll_write(cx, buf))?;
+        shared.frames.written(&buf[..written]);
+        if shared.frames.at_boundary() {
+            if let Some(reader) = shared.reader.take() {
+                reader.wake();
+            }
+        }
+        Poll::Ready(Ok(written))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        let mut shared = self.shared.lock().unwrap();
+        ready!(shared.poll_replies_first(cx))?;
+        Pin::new(&mut shared.stream).poll_flush(cx)
+    }
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let mut shared = self.shared.lock().unwrap();
+        ready!(shared.poll_replies_first(cx))?;
+        Pin::new(&mut shared.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,115 @@
+use futures_util::future::Future;
+use std::fmt::Display;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Delay;
+
+// how long each stage of a connection may take. None waits forever, which is the default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    //establishing the tcp connection
+    pub connect: Option<Duration>,
+    pub tls_handshake: Option<Duration>,
+    //sending the upgrade request and reading the response, for servers reading the request
+    pub upgrade: Option<Duration>,
+    //how long a read may go without receiving a single byte
+    pub idle_read: Option<Duration>,
+    //how long a write may go without getting anything out
+    pub write: Option<Duration>,
+}
+
+impl Timeouts {
+    // what servers use unless they're given others: a connection gets 10 seconds for the
+    // tls handshake and another 10 to send its upgrade request. Once upgraded it may idle.
+    pub fn for_server() -> Self {
+        Timeouts {
+            tls_handshake: Some(Duration::from_secs(10)),
+            upgrade: Some(Duration::from_secs(10)),
+            ..Timeouts::default()
+        }
+    }
+}
+
+// which of the Timeouts ran out. Comes wrapped in an io::Error of kind TimedOut,
+// get it back out with `e.get_ref().and_then(|e| e.downcast_ref::<Timeout>())`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout {
+    Connect,
+    TlsHandshake,
+    Upgrade,
+    IdleRead,
+    Write,
+}
+
+impl Display for Timeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Timeout::Connect => write!(f, "timed out connecting"),
+            Timeout::TlsHandshake => write!(f, "timed out during the tls handshake"),
+            Timeout::Upgrade => write!(f, "timed out waiting for the upgrade response"),
+            Timeout::IdleRead => write!(f, "timed out waiting for data"),
+            Timeout::Write => write!(f, "timed out writing"),
+        }
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+impl From<Timeout> for std::io::Error {
+    fn from(e: Timeout) -> Self {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, e)
+    }
+}
+
+// runs `future` to completion, or fails with `kind` if it takes longer than `limit`.
+pub(crate) async fn with_timeout<T, F>(
+    limit: Option<Duration>,
+    kind: Timeout,
+    future: F,
+) -> Result<T, std::io::Error>
+where
+    F: Future<Output = Result<T, std::io::Error>>,
+{
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future)
+            .await
+            .unwrap_or_else(|_| Err(kind.into())),
+        None => future.await,
+    }
+}
+
+// a timer for poll based io. It starts when an operation has to wait and is cleared
+// whenever the operation makes progress, so it only fires when nothing happens at all.
+#[derive(Debug, Default)]
+pub(crate) struct IdleTimer {
+    delay: Option<Delay>,
+}
+
+impl IdleTimer {
+    // call when the operation returned Pending.
+    pub(crate) fn poll_expired(
+        &mut self,
+        cx: &mut Context<'_>,
+        limit: Option<Duration>,
+        kind: Timeout,
+    ) -> Poll<std::io::Error> {
+        let limit = match limit {
+            Some(limit) => limit,
+            None => return Poll::Pending,
+        };
+        let delay = self
+            .delay
+            .get_or_insert_with(|| tokio::time::delay_for(limit));
+        match Pin::new(delay).poll(cx) {
+            Poll::Ready(()) => {
+                self.delay = None;
+                Poll::Ready(kind.into())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+    pub(crate) fn clear(&mut self) {
+        self.delay = None;
+    }
+}
@@ -0,0 +1,266 @@
+use crate::admission::{Admission, Limits};
+use crate::server::{accept, refuse, Router, REFUSE_DEADLINE};
+use crate::shutdown::{Shutdown, ShutdownHandle};
+use crate::timeout::{with_timeout, Timeout, Timeouts};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::{
+    internal::pemfile, sign, AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+    Certificate, ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert, RootCertStore,
+    ServerConfig,
+};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+fn invalid_data(reason: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+// every certificate in a PEM file, e.g. a chain with the leaf first.
+pub fn load_certs<P: AsRef<Path>>(path: P) -> Result<Vec<Certificate>, std::io::Error> {
+    let path = path.as_ref();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    match pemfile::certs(&mut reader) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(invalid_data(format!(
+            "no certificates found in {}",
+            path.display()
+        ))),
+    }
+}
+
+// the first private key in a PEM file, either PKCS#8 or PKCS#1 (RSA).
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<PrivateKey, std::io::Error> {
+    let path = path.as_ref();
+    let pem = std::fs::read(path)?;
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| invalid_data(format!("no private key found in {}", path.display())))
+}
+
+// picks the certificate by the name the client asked for, falling back to the default
+// for clients that don't send one or ask for a name we don't have.
+struct CertResolver {
+    default: Option<sign::CertifiedKey>,
+    by_name: HashMap<String, sign::CertifiedKey>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<sign::CertifiedKey> {
+        client_hello
+            .server_name()
+            .and_then(|name| {
+                let name: &str = name.into();
+                self.by_name.get(&name.to_ascii_lowercase())
+            })
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+// the tls side of a SecureServer: which certificates to present and whether to ask
+// clients for one of theirs.
+pub struct TlsServerConfig {
+    resolver: CertResolver,
+    //roots to check client certificates against, and whether clients must present one
+    client_auth: Option<(RootCertStore, bool)>,
+}
+
+impl std::fmt::Debug for TlsServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsServerConfig")
+            .field("default", &self.resolver.default.is_some())
+            .field("names", &self.resolver.by_name.keys().collect::<Vec<_>>())
+            .field(
+                "client_auth",
+                &self.client_auth.as_ref().map(|(_, required)| required),
+            )
+            .finish()
+    }
+}
+
+impl Default for TlsServerConfig {
+    fn default() -> Self {
+        TlsServerConfig {
+            resolver: CertResolver {
+                default: None,
+                by_name: HashMap::new(),
+            },
+            client_auth: None,
+        }
+    }
+}
+
+fn certified_key<P: AsRef<Path>, Q: AsRef<Path>>(
+    certs: P,
+    key: Q,
+) -> Result<sign::CertifiedKey, std::io::Error> {
+    let key_path = key.as_ref();
+    let key = sign::any_supported_type(&load_private_key(key_path)?)
+        .map_err(|_| invalid_data(format!("unsupported private key in {}", key_path.display())))?;
+    Ok(sign::CertifiedKey::new(load_certs(certs)?, Arc::new(key)))
+}
+
+impl TlsServerConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // the certificate for clients that don't use SNI, or ask for a name without its own.
+    pub fn with_cert<P: AsRef<Path>, Q: AsRef<Path>>(
+        mut self,
+        certs: P,
+        key: Q,
+    ) -> Result<Self, std::io::Error> {
+        self.resolver.default = Some(certified_key(certs, key)?);
+        Ok(self)
+    }
+    // the certificate for clients asking for `name`, which it has to be valid for.
+    pub fn with_sni_cert<P: AsRef<Path>, Q: AsRef<Path>>(
+        mut self,
+        name: &str,
+        certs: P,
+        key: Q,
+    ) -> Result<Self, std::io::Error> {
+        let certified_key = certified_key(certs, key)?;
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str(name)
+            .map_err(|_| invalid_data(format!("invalid server name {}", name)))?;
+        certified_key
+            .cross_check_end_entity_cert(Some(dns_name))
+            .map_err(|e| invalid_data(format!("certificate for {}: {}", name, e)))?;
+        self.resolver
+            .by_name
+            .insert(name.to_ascii_lowercase(), certified_key);
+        Ok(self)
+    }
+    // asks clients for a certificate signed by one of the CAs in the PEM file. With
+    // `required` the handshake fails without one, otherwise it's up to the handler.
+    pub fn with_client_ca<P: AsRef<Path>>(
+        mut self,
+        ca_certs: P,
+        required: bool,
+    ) -> Result<Self, std::io::Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca_certs)? {
+            roots
+                .add(&cert)
+                .map_err(|e| invalid_data(format!("invalid ca certificate: {}", e)))?;
+        }
+        self.client_auth = Some((roots, required));
+        Ok(self)
+    }
+    pub fn build(self) -> Arc<ServerConfig> {
+        let verifier = match self.client_auth {
+            None => NoClientAuth::new(),
+            Some((roots, true)) => AllowAnyAuthenticatedClient::new(roots),
+            Some((roots, false)) => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+        };
+        let mut config = ServerConfig::new(verifier);
+        config.cert_resolver = Arc::new(self.resolver);
+        config.set_protocols(&[b"http/1.1".to_vec()]);
+        Arc::new(config)
+    }
+}
+
+// a Server for wss, terminating tls before the upgrade.
+pub struct SecureServer {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    router: Arc<Router<TlsStream<TcpStream>>>,
+    shutdown: Arc<Shutdown>,
+    admission: Admission,
+    timeouts: Timeouts,
+}
+
+impl std::fmt::Debug for SecureServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureServer")
+            .field("listener", &self.listener)
+            .field("router", &self.router)
+            .field("shutdown", &self.shutdown)
+            .field("admission", &self.admission)
+            .field("timeouts", &self.timeouts)
+            .finish()
+    }
+}
+
+impl SecureServer {
+    pub async fn bind<A: ToSocketAddrs>(
+        addr: A,
+        config: Arc<ServerConfig>,
+        router: Router<TlsStream<TcpStream>>,
+    ) -> Result<Self, std::io::Error> {
+        Ok(SecureServer {
+            listener: TcpListener::bind(addr).await?,
+            acceptor: TlsAcceptor::from(config),
+            router: Arc::new(router),
+            shutdown: Shutdown::new(),
+            admission: Admission::default(),
+            timeouts: Timeouts::for_server(),
+        })
+    }
+    pub fn with_limits(self, limits: Limits) -> Self {
+        self.admission.set_limits(limits);
+        self
+    }
+    // Timeouts::for_server unless set, see Server::with_timeouts.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+    pub fn admission(&self) -> Admission {
+        self.admission.clone()
+    }
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr, std::io::Error> {
+        self.listener.local_addr()
+    }
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.shutdown.clone())
+    }
+    // returns with Ok once a shutdown has started. Failed and timed out handshakes are
+    // dropped, failed accepts are logged.
+    pub async fn run(mut self) -> Result<(), std::io::Error> {
+        loop {
+            let (stream, peer) = tokio::select! {
+                accepted = accept(&mut self.listener) => accepted,
+                _ = self.shutdown.going_away() => return Ok(()),
+            };
+            //counted before the handshake, that's the expensive part
+            let admitted = match self.admission.admit(peer.ip()) {
+                Ok(admitted) => Ok(admitted),
+                //too many are being refused already, this one is just closed
+                Err(refusal) => match self.admission.refusing() {
+                    Some(refusing) => Err((refusal, refusing)),
+                    None => continue,
+                },
+            };
+            let acceptor = self.acceptor.clone();
+            let router = self.router.clone();
+            let signal = self.shutdown.going_away_signal();
+            let timeouts = self.timeouts;
+            tokio::spawn(self.shutdown.track(async move {
+                //a refused connection only gets REFUSE_DEADLINE for its handshake as well
+                let limit = match admitted {
+                    Ok(_) => timeouts.tls_handshake,
+                    Err(_) => Some(
+                        timeouts
+                            .tls_handshake
+                            .map_or(REFUSE_DEADLINE, |limit| limit.min(REFUSE_DEADLINE)),
+                    ),
+                };
+                let handshake = acceptor.accept(stream);
+                let handshake = with_timeout(limit, Timeout::TlsHandshake, handshake);
+                if let Ok(stream) = handshake.await {
+                    let _ = match admitted {
+                        Ok(_admitted) => router.serve_with(stream, Some(signal), timeouts).await,
+                        Err((refusal, _refusing)) => refuse(stream, refusal).await,
+                    };
+                }
+            }));
+        }
+    }
+}
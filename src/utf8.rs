@@ -0,0 +1,89 @@
+use std::fmt::Display;
+
+// text messages can be split over several frames, and a frame boundary can fall
+// in the middle of a code point. We keep the (at most 3) bytes of an unfinished
+// code point around and complete it with the start of the next fragment.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8Validator {
+    partial: [u8; 4],
+    partial_len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidUtf8;
+
+impl InvalidUtf8 {
+    // RFC 6455 7.4.1: 1007 indicates inconsistent data within a message.
+    pub fn close_code(&self) -> u16 {
+        1007
+    }
+}
+
+impl Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "text message contains invalid utf-8")
+    }
+}
+
+impl std::error::Error for InvalidUtf8 {}
+
+impl From<InvalidUtf8> for std::io::Error {
+    fn from(e: InvalidUtf8) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+// number of bytes in the code point started by `byte`, 0 if it can't start one.
+fn sequence_width(byte: u8) -> usize {
+    match byte {
+        0x00..=0x7F => 1,
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF4 => 4,
+        _ => 0,
+    }
+}
+
+impl Utf8Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // validates the next chunk of a message. Fails as soon as the bytes seen so far
+    // can no longer be the start of valid utf-8, even if the code point isn't complete yet.
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<(), InvalidUtf8> {
+        if self.partial_len > 0 {
+            let width = sequence_width(self.partial[0]);
+            let needed = (width - self.partial_len).min(data.len());
+            self.partial[self.partial_len..self.partial_len + needed]
+                .copy_from_slice(&data[..needed]);
+            self.partial_len += needed;
+            data = &data[needed..];
+            match std::str::from_utf8(&self.partial[..self.partial_len]) {
+                Ok(_) => self.partial_len = 0,
+                Err(e) if e.error_len().is_none() => return Ok(()),
+                Err(_) => return Err(InvalidUtf8),
+            }
+        }
+        match std::str::from_utf8(data) {
+            Ok(_) => Ok(()),
+            //the data ends in the middle of a valid code point, stash the tail for next time
+            Err(e) if e.error_len().is_none() => {
+                let tail = &data[e.valid_up_to()..];
+                self.partial[..tail.len()].copy_from_slice(tail);
+                self.partial_len = tail.len();
+                Ok(())
+            }
+            Err(_) => Err(InvalidUtf8),
+        }
+    }
+    // call when the final fragment has been fed, a message can't end halfway a code point.
+    pub fn finish(&mut self) -> Result<(), InvalidUtf8> {
+        let complete = self.partial_len == 0;
+        self.partial_len = 0;
+        if complete {
+            Ok(())
+        } else {
+            Err(InvalidUtf8)
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::frame::{Frame, Opcode, Role};
+use std::fmt::Display;
+
+// the reserved bits that negotiated extensions have given a meaning,
+// e.g. permessage-deflate uses rsv1 to flag compressed messages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    pub rsv1: bool,
+    pub rsv2: bool,
+    pub rsv3: bool,
+}
+
+impl Extensions {
+    pub fn none() -> Self {
+        Self::default()
+    }
+    pub fn permessage_deflate() -> Self {
+        Extensions {
+            rsv1: true,
+            ..Self::default()
+        }
+    }
+}
+
+// how to treat payload lengths that use more bytes than needed, e.g. 5 sent as a 2 byte length.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LengthEncoding {
+    //reject them, RFC 6455 5.2 says the minimal number of bytes MUST be used
+    #[default]
+    Strict,
+    //accept them, for talking to peers that are known to pad their lengths
+    Lenient,
+}
+
+// everything RFC 6455 says can be wrong with a single frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    //a reserved bit is set that no extension has claimed, holds the rsv bits in place (0b0xxx_0000)
+    ReservedBits(u8),
+    ReservedOpcode(u8),
+    //servers must not mask, clients must.
+    MaskedFrame,
+    UnmaskedFrame,
+    ControlFrameTooLarge(usize),
+    FragmentedControlFrame,
+    //the length used more bytes than needed to encode it
+    NonMinimalLength,
+    //the most significant bit of a 64 bit length must be 0
+    LengthOverflow,
+    //a close frame payload is either empty or starts with a 2 byte code
+    ClosePayloadTooShort,
+    InvalidCloseCode(u16),
+    InvalidCloseReason,
+}
+
+impl FrameError {
+    // the code to close the connection with when receiving a frame like this.
+    pub fn close_code(&self) -> u16 {
+        match self {
+            FrameError::InvalidCloseReason => 1007,
+            _ => 1002,
+        }
+    }
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::ReservedBits(bits) => write!(f, "reserved bits {:#010b} are set", bits),
+            FrameError::ReservedOpcode(opcode) => write!(f, "reserved opcode {:#x}", opcode),
+            FrameError::MaskedFrame => write!(f, "frame from the server is masked"),
+            FrameError::UnmaskedFrame => write!(f, "frame from the client is not masked"),
+            FrameError::ControlFrameTooLarge(len) => {
+                write!(f, "control frame payload of {} bytes exceeds 125", len)
+            }
+            FrameError::FragmentedControlFrame => write!(f, "control frame is fragmented"),
+            FrameError::NonMinimalLength => write!(f, "payload length is not minimally encoded"),
+            FrameError::LengthOverflow => write!(f, "payload length has its highest bit set"),
+            FrameError::ClosePayloadTooShort => write!(f, "close frame payload of 1 byte"),
+            FrameError::InvalidCloseCode(code) => write!(f, "invalid close code {}", code),
+            FrameError::InvalidCloseReason => write!(f, "close reason is not valid utf-8"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for std::io::Error {
+    fn from(e: FrameError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+// a frame or message past the limits set on the Client that's reading it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TooBig {
+    //the payload length from the frame header, and the limit
+    Frame(usize, usize),
+    //the size of the message so far, and the limit
+    Message(usize, usize),
+}
+
+impl TooBig {
+    // RFC 6455 7.4.1: 1009 is for messages too big to process.
+    pub fn close_code(&self) -> u16 {
+        1009
+    }
+}
+
+impl Display for TooBig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TooBig::Frame(size, limit) => {
+                write!(f, "frame of {} bytes exceeds the limit of {}", size, limit)
+            }
+            TooBig::Message(size, limit) => {
+                write!(
+                    f,
+                    "message of {} bytes exceeds the limit of {}",
+                    size, limit
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for TooBig {}
+
+impl From<TooBig> for std::io::Error {
+    fn from(e: TooBig) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+// RFC 6455 7.4: codes an endpoint may send, plus the ones IANA registered since.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+impl Frame {
+    // checks the frame on its own, things that span frames (like interleaved
+    // fragments) are up to the reader.
+    pub fn validate(&self, role: Role, extensions: Extensions) -> Result<(), FrameError> {
+        self.validate_with(role, extensions, LengthEncoding::Strict)
+    }
+    pub fn validate_with(
+        &self,
+        role: Role,
+        extensions: Extensions,
+        lengths: LengthEncoding,
+    ) -> Result<(), FrameError> {
+        let mut reserved = 0;
+        if self.rsv1() && !extensions.rsv1 {
+            reserved |= 1 << 6;
+        }
+        if self.rsv2() && !extensions.rsv2 {
+            reserved |= 1 << 5;
+        }
+        if self.rsv3() && !extensions.rsv3 {
+            reserved |= 1 << 4;
+        }
+        if reserved != 0 {
+            return Err(FrameError::ReservedBits(reserved));
+        }
+        match (role.expects_masked(), self.has_mask()) {
+            (false, true) => return Err(FrameError::MaskedFrame),
+            (true, false) => return Err(FrameError::UnmaskedFrame),
+            _ => {}
+        }
+        if self.payload_len() >> 63 != 0 {
+            return Err(FrameError::LengthOverflow);
+        }
+        if lengths == LengthEncoding::Strict && !self.has_minimal_length() {
+            return Err(FrameError::NonMinimalLength);
+        }
+        match self.opcode() {
+            Opcode::Invalid(opcode) => Err(FrameError::ReservedOpcode(opcode)),
+            Opcode::Close | Opcode::Ping | Opcode::Pong if self.data_len() > 125 => {
+                Err(FrameError::ControlFrameTooLarge(self.data_len()))
+            }
+            Opcode::Close | Opcode::Ping | Opcode::Pong if !self.fin() => {
+                Err(FrameError::FragmentedControlFrame)
+            }
+            Opcode::Close => self.validate_close_payload(),
+            _ => Ok(()),
+        }
+    }
+    fn validate_close_payload(&self) -> Result<(), FrameError> {
+        //control frames are small, unmask a copy rather than require an unmasked frame
+        let mut payload = [0u8; 125];
+        let payload = &mut payload[..self.data_len()];
+        payload.copy_from_slice(self.masked_data());
+        if let Some(mask) = self.mask() {
+            crate::mask::apply_mask(payload, mask);
+        }
+        match payload.len() {
+            0 => Ok(()),
+            1 => Err(FrameError::ClosePayloadTooShort),
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !is_valid_close_code(code) {
+                    Err(FrameError::InvalidCloseCode(code))
+                } else if std::str::from_utf8(&payload[2..]).is_err() {
+                    Err(FrameError::InvalidCloseReason)
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::SystemTime;
+use tokio_rustls::rustls::{
+    Certificate, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+// the same algorithms rustls lets webpki use for names.
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+// checks the server's certificate against an ip address. webpki only matches dns names,
+// so the chain is checked by webpki and the iPAddress entries of the subjectAltName here.
+pub(crate) struct IpAddrVerifier {
+    pub(crate) ip: IpAddr,
+}
+
+impl ServerCertVerifier for IpAddrVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let (end_entity, intermediates) = presented_certs
+            .split_first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let cert = webpki::EndEntityCert::from(&end_entity.0).map_err(TLSError::WebPKIError)?;
+        let intermediates = intermediates
+            .iter()
+            .map(|cert| cert.0.as_ref())
+            .collect::<Vec<_>>();
+        let anchors = roots
+            .roots
+            .iter()
+            .map(|anchor| anchor.to_trust_anchor())
+            .collect::<Vec<_>>();
+        let now = webpki::Time::try_from(SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+        cert.verify_is_valid_tls_server_cert(
+            SUPPORTED_SIG_ALGS,
+            &webpki::TLSServerTrustAnchors(&anchors),
+            &intermediates,
+            now,
+        )
+        .map_err(TLSError::WebPKIError)?;
+        if ip_addresses(&end_entity.0).contains(&self.ip) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName))
+        }
+    }
+}
+
+// the iPAddress entries of the certificate's subjectAltName, none if it can't be read.
+fn ip_addresses(cert: &[u8]) -> Vec<IpAddr> {
+    let cert = match X509Certificate::from_der(cert) {
+        Ok((_, cert)) => cert,
+        Err(_) => return vec![],
+    };
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(names)) => names.value,
+        _ => return vec![],
+    };
+    names
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            //4 bytes for ipv4 and 16 for ipv6
+            GeneralName::IPAddress(octets) => <[u8; 4]>::try_from(*octets)
+                .map(IpAddr::from)
+                .or_else(|_| <[u8; 16]>::try_from(*octets).map(IpAddr::from))
+                .ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::load_certs;
+
+    fn cert(name: &str) -> Certificate {
+        let path = format!("{}/tests/certs/{}.pem", env!("CARGO_MANIFEST_DIR"), name);
+        load_certs(path).unwrap().remove(0)
+    }
+
+    fn verify(ip: &str, cert: Certificate) -> Result<(), TLSError> {
+        let mut roots = RootCertStore::empty();
+        roots.add(&self::cert("ca")).unwrap();
+        let verifier = IpAddrVerifier {
+            ip: ip.parse().unwrap(),
+        };
+        let dns_name = webpki::DNSNameRef::try_from_ascii_str("ip-address.invalid").unwrap();
+        verifier
+            .verify_server_cert(&roots, &[cert], dns_name, &[])
+            .map(|_| ())
+    }
+
+    #[test]
+    fn reads_ipv4_and_ipv6_entries() {
+        //any real certificate uses long form lengths, this one is a few hundred bytes
+        assert_eq!(
+            ip_addresses(&cert("ip").0),
+            [
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert!(ip_addresses(&cert("dns").0).is_empty());
+    }
+
+    #[test]
+    fn unreadable_certificates_have_no_addresses() {
+        let der = cert("ip").0;
+        for len in 0..der.len() {
+            assert!(ip_addresses(&der[..len]).is_empty(), "{} bytes", len);
+        }
+        assert!(ip_addresses(&[0x30, 0x84, 0xFF, 0xFF, 0xFF, 0xFF]).is_empty());
+        assert!(ip_addresses(b"not a certificate").is_empty());
+    }
+
+    #[test]
+    fn accepts_only_the_addresses_on_the_certificate() {
+        assert!(verify("127.0.0.1", cert("ip")).is_ok());
+        assert!(verify("::1", cert("ip")).is_ok());
+        for ip in ["10.0.0.1", "::2", "::ffff:127.0.0.1"] {
+            let e = verify(ip, cert("ip")).unwrap_err();
+            assert_eq!(e, TLSError::WebPKIError(webpki::Error::CertNotValidForName));
+        }
+        //the name on it doesn't count for an address
+        assert!(verify("127.0.0.1", cert("dns")).is_err());
+    }
+
+    #[test]
+    fn rejects_broken_certificates() {
+        let der = cert("ip").0;
+        assert!(verify("127.0.0.1", Certificate(der[..der.len() - 1].to_vec())).is_err());
+        assert!(verify("127.0.0.1", Certificate(b"not a certificate".to_vec())).is_err());
+        //signed by someone the roots don't know
+        let mut tampered = der;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(verify("127.0.0.1", Certificate(tampered)).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+// the reconnect delay curve, its cap and the jitter bounds, and settings that make no sense.
+use std::time::Duration;
+use yaws::Backoff;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn backoff(multiplier: f64, jitter: f64) -> Backoff {
+    Backoff {
+        initial: ms(100),
+        max: ms(1000),
+        multiplier,
+        jitter,
+    }
+}
+
+#[test]
+fn grows_until_the_cap() {
+    let backoff = backoff(2.0, 0.0);
+    let delays: Vec<_> = (1..=6).map(|attempt| backoff.delay(attempt)).collect();
+    assert_eq!(
+        delays,
+        [ms(100), ms(200), ms(400), ms(800), ms(1000), ms(1000)]
+    );
+    assert_eq!(backoff.delay(0), ms(100));
+    assert_eq!(backoff.delay(u32::MAX), ms(1000));
+}
+
+#[test]
+fn jitter_only_shortens_the_delay() {
+    let backoff = backoff(2.0, 0.25);
+    for _ in 0..1000 {
+        let delay = backoff.delay(3);
+        assert!(delay >= ms(300) && delay <= ms(400), "{:?}", delay);
+        let capped = backoff.delay(10);
+        assert!(capped >= ms(750) && capped <= ms(1000), "{:?}", capped);
+    }
+}
+
+#[test]
+fn clamps_out_of_range_settings() {
+    //a multiplier below 1 never lets the delay shrink
+    for multiplier in [-2.0, 0.0, 0.5, f64::NAN, f64::NEG_INFINITY] {
+        let backoff = backoff(multiplier, 0.0);
+        assert_eq!(backoff.delay(1), ms(100));
+        assert_eq!(backoff.delay(4), ms(100));
+    }
+    assert_eq!(backoff(f64::INFINITY, 0.0).delay(2), ms(1000));
+    //jitter past 1 can take the whole delay, but never more
+    for _ in 0..1000 {
+        assert!(backoff(2.0, 5.0).delay(2) <= ms(200));
+    }
+    for jitter in [-1.0, f64::NAN] {
+        assert_eq!(backoff(2.0, jitter).delay(2), ms(200));
+    }
+    let unbounded = Backoff {
+        max: Duration::MAX,
+        jitter: 0.0,
+        ..Backoff::default()
+    };
+    assert_eq!(unbounded.delay(u32::MAX), Duration::MAX);
+}
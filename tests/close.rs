@@ -0,0 +1,140 @@
+// nothing goes out after a close frame: not from the caller, and not after the 1001 a server
+// that's shutting down sends on its own.
+use futures_util::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::net::TcpStream;
+use tokio_util::codec::FramedRead;
+use yaws::{Client, Frame, Message, Role, Router, Server, WsCodec};
+
+fn not_connected(result: Result<(), std::io::Error>) {
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::NotConnected);
+}
+
+async fn frames_sent(peer: DuplexStream) -> Vec<Message> {
+    FramedRead::new(peer, WsCodec::new(Role::Client))
+        .map(|frame| {
+            let frame = frame.unwrap();
+            match frame.close_code() {
+                Some(code) => Message::Close(Some(code)),
+                None => Message::Binary(frame.unmasked_data().to_vec()),
+            }
+        })
+        .collect()
+        .await
+}
+
+#[tokio::test]
+async fn sends_fail_after_the_close() {
+    let (stream, peer) = tokio::io::duplex(1 << 16);
+    let mut client = Client::from_upgraded(stream, Role::Server);
+    client.send_binary(b"before").await.unwrap();
+    client.send_close(Some(1000)).await.unwrap();
+
+    not_connected(client.send_str("after").await);
+    not_connected(client.send_binary(b"after").await);
+    not_connected(client.ping(Some(b"after")).await);
+    not_connected(client.pong(None).await);
+    not_connected(
+        client
+            .send_encoded(&Frame::new_binary(b"after", None, true))
+            .await,
+    );
+    not_connected(client.send(Message::Text("after".into())).await);
+    //a second close is skipped
+    client.send_close(Some(1011)).await.unwrap();
+    client.send(Message::Close(None)).await.unwrap();
+    drop(client);
+
+    assert_eq!(
+        frames_sent(peer).await,
+        [
+            Message::Binary(b"before".to_vec()),
+            Message::Close(Some(1000))
+        ]
+    );
+}
+
+#[tokio::test]
+async fn the_sink_refuses_after_the_close() {
+    let (stream, peer) = tokio::io::duplex(1 << 16);
+    let mut client = Client::from_upgraded(stream, Role::Server);
+    client.feed(Message::Close(Some(1000))).await.unwrap();
+    not_connected(client.feed(Message::Binary(b"after".to_vec())).await);
+    SinkExt::<Message>::close(&mut client).await.unwrap();
+    assert_eq!(frames_sent(peer).await, [Message::Close(Some(1000))]);
+}
+
+// how a handler that never reads writes: straight to the client, through the writer half
+// of `split`, or through a Sender.
+#[derive(Debug, Clone, Copy)]
+enum Writer {
+    Client,
+    Split,
+    Sender,
+}
+
+async fn write_until_it_fails(client: Client<TcpStream>, writer: Writer) {
+    let tick = || tokio::time::delay_for(Duration::from_millis(5));
+    match writer {
+        Writer::Client => {
+            let mut client = client;
+            while client.send_str("tick").await.is_ok() {
+                tick().await;
+            }
+        }
+        Writer::Split => {
+            let (_reader, mut writer) = client.split();
+            while writer.send_str("tick").await.is_ok() {
+                tick().await;
+            }
+        }
+        Writer::Sender => {
+            let mut sender = client.into_handle();
+            while sender.send("tick").await.is_ok() {
+                tick().await;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn write_only_handlers_end_with_one_going_away_close() {
+    for writer in [Writer::Client, Writer::Split, Writer::Sender] {
+        let router =
+            Router::new().route("/", move |client, _| write_until_it_fails(client, writer));
+        let server = Server::bind("127.0.0.1:0", router).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        tokio::spawn(server.run());
+
+        let mut client = Client::connect_insecure(format!("ws://{}/", addr))
+            .await
+            .unwrap();
+        let mut received = vec![];
+        //a few ticks in, then shut down
+        while received.len() < 3 {
+            received.push(client.next().await.unwrap().unwrap());
+        }
+        let shutting_down = tokio::spawn(async move {
+            shutdown
+                .shutdown("restarting", Duration::from_secs(5))
+                .await
+        });
+        while let Some(Ok(message)) = client.next().await {
+            received.push(message);
+        }
+        assert_eq!(shutting_down.await.unwrap(), 0, "{:?}", writer);
+
+        let (last, ticks) = received.split_last().unwrap();
+        assert_eq!(*last, Message::Close(Some(1001)), "{:?}", writer);
+        assert!(
+            ticks
+                .iter()
+                .all(|message| *message == Message::Text("tick".into())),
+            "{:?}: {:?}",
+            writer,
+            received
+        );
+    }
+}
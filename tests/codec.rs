@@ -0,0 +1,147 @@
+// WsCodec round trips between the two roles, and rejects what a Client would reject.
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+use yaws::{Extensions, Frame, FrameBuf, FrameError, Message, Opcode, Role, TooBig, WsCodec};
+
+fn frame_error(e: &std::io::Error) -> FrameError {
+    *e.get_ref().unwrap().downcast_ref::<FrameError>().unwrap()
+}
+
+fn encode(role: Role, message: Message) -> BytesMut {
+    let mut buf = BytesMut::new();
+    WsCodec::new(role).encode(message, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn round_trips_between_client_and_server() {
+    for (from, to) in [(Role::Client, Role::Server), (Role::Server, Role::Client)] {
+        let mut buf = encode(from, Message::Text("hello".into()));
+        buf.extend_from_slice(&encode(from, Message::Binary(vec![7; 300])));
+        buf.extend_from_slice(&encode(from, Message::Close(Some(1001))));
+
+        let mut codec = WsCodec::new(to);
+        let text = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(text.opcode(), Opcode::Text);
+        assert_eq!(text.unmasked_data(), b"hello");
+        let binary = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(binary.unmasked_data(), &[7; 300][..]);
+        let close = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(close.close_code(), Some(1001));
+        assert!(buf.is_empty());
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+}
+
+#[test]
+fn waits_for_the_rest_of_a_frame() {
+    let whole = encode(Role::Client, Message::Binary(vec![1; 200]));
+    let mut codec = WsCodec::new(Role::Server);
+    let mut buf = BytesMut::new();
+    for byte in &whole[..whole.len() - 1] {
+        buf.extend_from_slice(&[*byte]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+    buf.extend_from_slice(&whole[whole.len() - 1..]);
+    let frame = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(frame.unmasked_data(), &[1; 200][..]);
+}
+
+#[test]
+fn rejects_frames_masked_for_the_wrong_role() {
+    let mut from_server = encode(Role::Server, Message::Binary(vec![1, 2, 3]));
+    let e = WsCodec::new(Role::Server)
+        .decode(&mut from_server)
+        .unwrap_err();
+    assert_eq!(frame_error(&e), FrameError::UnmaskedFrame);
+
+    let mut from_client = encode(Role::Client, Message::Binary(vec![1, 2, 3]));
+    let e = WsCodec::new(Role::Client)
+        .decode(&mut from_client)
+        .unwrap_err();
+    assert_eq!(frame_error(&e), FrameError::MaskedFrame);
+
+    //from the header alone, before the payload arrives
+    let mut header = BytesMut::from(&[0x82, 100][..]);
+    let e = WsCodec::new(Role::Server).decode(&mut header).unwrap_err();
+    assert_eq!(frame_error(&e), FrameError::UnmaskedFrame);
+}
+
+#[test]
+fn rejects_oversized_frames_from_their_header() {
+    let mut codec = WsCodec::new(Role::Client).with_max_frame_size(1000);
+    let mut header = BytesMut::from(&[0x82, 126, 0x03, 0xE9][..]);
+    let e = codec.decode(&mut header).unwrap_err();
+    let too_big = *e.get_ref().unwrap().downcast_ref::<TooBig>().unwrap();
+    assert_eq!(too_big, TooBig::Frame(1001, 1000));
+    assert_eq!(too_big.close_code(), 1009);
+
+    let mut fits = encode(Role::Server, Message::Binary(vec![0; 1000]));
+    assert!(codec.decode(&mut fits).unwrap().is_some());
+}
+
+#[test]
+fn validates_decoded_frames() {
+    let mut codec = WsCodec::new(Role::Client);
+    //reserved opcode 0x3
+    let e = codec
+        .decode(&mut BytesMut::from(&[0x83, 0][..]))
+        .unwrap_err();
+    assert_eq!(frame_error(&e), FrameError::ReservedOpcode(3));
+    //a ping without fin
+    let e = codec
+        .decode(&mut BytesMut::from(&[0x09, 0][..]))
+        .unwrap_err();
+    assert_eq!(frame_error(&e), FrameError::FragmentedControlFrame);
+    //5 sent as a 2 byte length
+    let e = codec
+        .decode(&mut BytesMut::from(&[0x82, 126, 0, 5, 1, 2, 3, 4, 5][..]))
+        .unwrap_err();
+    assert_eq!(frame_error(&e), FrameError::NonMinimalLength);
+    //rsv1 is only allowed once an extension claims it
+    let compressed = [0xC1, 1, 0];
+    let e = codec
+        .decode(&mut BytesMut::from(&compressed[..]))
+        .unwrap_err();
+    assert!(matches!(frame_error(&e), FrameError::ReservedBits(_)));
+    let mut codec = WsCodec::new(Role::Client).with_extensions(Extensions::permessage_deflate());
+    let frame = codec
+        .decode(&mut BytesMut::from(&compressed[..]))
+        .unwrap()
+        .unwrap();
+    assert!(frame.rsv1());
+}
+
+#[test]
+fn encoders_check_masking_against_the_role() {
+    let mut buf = BytesMut::new();
+    let mut client = WsCodec::new(Role::Client);
+    let e = client
+        .encode(Frame::new_binary(b"abc", None, true), &mut buf)
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    client
+        .encode(Frame::new_binary(b"abc", Some(0x1234_5678), true), &mut buf)
+        .unwrap();
+
+    let mut server = WsCodec::new(Role::Server);
+    let masked = Frame::new_ping(None, Some(1)).as_bytes().to_vec();
+    let masked = FrameBuf::from_bytes(masked.into()).unwrap();
+    let e = server.encode(masked, &mut buf).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    let unmasked = FrameBuf::from_bytes(Frame::new_ping(None, None).as_bytes().to_vec().into());
+    server.encode(unmasked.unwrap(), &mut buf).unwrap();
+
+    //only the two accepted frames were written
+    let mut decoded = WsCodec::new(Role::Server)
+        .decode(&mut buf)
+        .unwrap()
+        .unwrap();
+    assert_eq!(decoded.unmasked_data(), b"abc");
+    decoded = WsCodec::new(Role::Client)
+        .decode(&mut buf)
+        .unwrap()
+        .unwrap();
+    assert_eq!(decoded.opcode(), Opcode::Ping);
+    assert!(buf.is_empty());
+}
@@ -0,0 +1,125 @@
+// dialing: ipv6 literals, custom resolvers and certificates for ip addresses.
+use futures_util::{future::BoxFuture, StreamExt};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio_rustls::rustls::ClientConfig;
+use yaws::{
+    load_certs, Client, ConnectOptions, Message, Resolver, Router, SecureServer, Server,
+    TlsServerConfig,
+};
+
+// answers with `addrs` and remembers what it was asked.
+#[derive(Debug, Default)]
+struct Recording {
+    addrs: Vec<SocketAddr>,
+    asked: Mutex<Vec<(String, u16)>>,
+}
+
+impl Resolver for Recording {
+    fn resolve<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+    ) -> BoxFuture<'a, Result<Vec<SocketAddr>, std::io::Error>> {
+        self.asked.lock().unwrap().push((host.to_owned(), port));
+        Box::pin(async move { Ok(self.addrs.clone()) })
+    }
+}
+
+// echoes the Host header the client sent.
+fn host_echo<S>() -> Router<S>
+where
+    S: std::marker::Unpin + tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+{
+    Router::new().route("/", |mut client, request| async move {
+        let host = request.header("host").unwrap_or_default().to_owned();
+        let _ = client.send_str(host).await;
+    })
+}
+
+async fn serve(addr: &str) -> SocketAddr {
+    let server = Server::bind(addr, host_echo()).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+    addr
+}
+
+async fn host_seen_by<S>(mut client: Client<S>) -> Message
+where
+    S: std::marker::Unpin + tokio::io::AsyncRead + tokio::io::AsyncWrite,
+{
+    client.next().await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn ipv6_literals_are_dialled_without_the_resolver() {
+    let addr = serve("[::1]:0").await;
+    let resolver = Arc::new(Recording::default());
+    let options = ConnectOptions {
+        resolver: Some(resolver.clone()),
+        ..ConnectOptions::default()
+    };
+    let uri = format!("ws://[::1]:{}/", addr.port());
+    let client = Client::connect_insecure_with(uri, &options).await.unwrap();
+    //the Host header keeps the brackets
+    let expected = format!("[::1]:{}", addr.port());
+    assert_eq!(host_seen_by(client).await, Message::Text(expected));
+    assert!(resolver.asked.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn names_go_through_the_resolver_and_fall_back_across_addresses() {
+    let addr = serve("[::1]:0").await;
+    //nothing listens on this one
+    let closed_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed = closed_listener.local_addr().unwrap();
+    drop(closed_listener);
+    let resolver = Arc::new(Recording {
+        addrs: vec![closed, addr],
+        ..Recording::default()
+    });
+    let options = ConnectOptions {
+        resolver: Some(resolver.clone()),
+        ..ConnectOptions::default()
+    };
+    let uri = format!("ws://name.test:{}/", addr.port());
+    let client = Client::connect_insecure_with(uri, &options).await.unwrap();
+    let expected = format!("name.test:{}", addr.port());
+    assert_eq!(host_seen_by(client).await, Message::Text(expected));
+    assert_eq!(
+        *resolver.asked.lock().unwrap(),
+        [("name.test".to_owned(), addr.port())]
+    );
+}
+
+#[tokio::test]
+async fn certificates_are_checked_against_ip_addresses() {
+    let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/");
+    let mut tls_config = ClientConfig::new();
+    for cert in load_certs(format!("{}ca.pem", certs)).unwrap() {
+        tls_config.root_store.add(&cert).unwrap();
+    }
+    let options = ConnectOptions::new().with_tls_config(Arc::new(tls_config));
+
+    for (name, valid) in [("ip", true), ("dns", false)] {
+        let config = TlsServerConfig::new()
+            .with_cert(
+                format!("{}{}.pem", certs, name),
+                format!("{}{}.key", certs, name),
+            )
+            .unwrap()
+            .build();
+        let server = SecureServer::bind("[::1]:0", config, host_echo())
+            .await
+            .unwrap();
+        let port = server.local_addr().unwrap().port();
+        tokio::spawn(server.run());
+        let uri = format!("wss://[::1]:{}/", port);
+        let connected = Client::connect_secure_with(uri, &options).await;
+        assert_eq!(connected.is_ok(), valid, "{} certificate", name);
+        //the address on the certificate has to be the one dialled, resumed sessions too
+        let other = options.clone().with_server_name("10.0.0.1");
+        let uri = format!("wss://[::1]:{}/", port);
+        assert!(Client::connect_secure_with(uri, &other).await.is_err());
+    }
+}
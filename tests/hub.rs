@@ -0,0 +1,137 @@
+// fanning out through a Hub over in-memory connections: what members that can't keep up
+// get under each policy, and how members leave their rooms.
+use futures_util::StreamExt;
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio_util::codec::FramedRead;
+use yaws::{Client, FrameBuf, Hub, Member, Message, Opcode, Role, SlowConsumer, WsCodec};
+
+// a member whose peer can hold `capacity` bytes before it has to read.
+fn add(hub: &Hub, policy: SlowConsumer, capacity: usize) -> (Member, DuplexStream) {
+    let (stream, peer) = tokio::io::duplex(capacity);
+    let member = hub
+        .add(Client::from_upgraded(stream, Role::Server), policy)
+        .unwrap();
+    (member, peer)
+}
+
+// every frame the member is sent until its connection ends.
+async fn frames(peer: DuplexStream) -> Vec<FrameBuf> {
+    FramedRead::new(peer, WsCodec::new(Role::Client))
+        .map(Result::unwrap)
+        .collect()
+        .await
+}
+
+fn text(frame: &FrameBuf) -> &str {
+    assert_eq!(frame.opcode(), Opcode::Text);
+    std::str::from_utf8(frame.unmasked_data()).unwrap()
+}
+
+fn message(n: usize) -> Message {
+    Message::Text(format!("message {}", n))
+}
+
+#[tokio::test]
+async fn drop_skips_messages_while_the_queue_is_full() {
+    let hub = Hub::new().with_queue_len(2);
+    //too small for even one frame, the first one gets stuck partway out
+    let (member, peer) = add(&hub, SlowConsumer::Drop, 8);
+    member.join("room");
+    let delivered: Vec<_> = (0..5).map(|n| hub.publish("room", &message(n))).collect();
+    assert_eq!(delivered, [1, 1, 0, 0, 0]);
+    assert_eq!(hub.members("room"), 1);
+
+    let mut peer = FramedRead::new(peer, WsCodec::new(Role::Client));
+    assert_eq!(text(&peer.next().await.unwrap().unwrap()), "message 0");
+    assert_eq!(text(&peer.next().await.unwrap().unwrap()), "message 1");
+    //caught up, so it gets messages again
+    assert_eq!(hub.publish("room", &message(5)), 1);
+    assert_eq!(text(&peer.next().await.unwrap().unwrap()), "message 5");
+}
+
+#[tokio::test]
+async fn falling_behind_closes_with_1008_and_leaves_every_room() {
+    //"message n" is 11 bytes as a frame, two of them don't fit in 20
+    for policy in [SlowConsumer::Disconnect, SlowConsumer::Buffer(20)] {
+        let hub = Hub::new().with_queue_len(1);
+        let (member, peer) = add(&hub, policy, 1 << 16);
+        member.join("a");
+        member.join("b");
+        //published back to back, before the member's task gets to write anything
+        assert_eq!(hub.publish("a", &message(0)), 1);
+        assert_eq!(hub.publish("b", &message(1)), 0);
+        assert_eq!((hub.members("a"), hub.members("b")), (0, 0), "{:?}", policy);
+
+        let frames = frames(peer).await;
+        let (close, rest) = frames.split_last().unwrap();
+        assert_eq!(close.close_code(), Some(1008), "{:?}", policy);
+        assert!(rest.iter().all(|frame| text(frame) == "message 0"));
+        drop(member);
+    }
+}
+
+#[tokio::test]
+async fn a_frame_partway_out_is_finished_before_the_close() {
+    let hub = Hub::new().with_queue_len(1);
+    let (member, peer) = add(&hub, SlowConsumer::Disconnect, 8);
+    member.join("room");
+    assert_eq!(hub.publish("room", &message(0)), 1);
+    //let it write the first 8 bytes of the frame
+    tokio::time::delay_for(Duration::from_millis(10)).await;
+    assert_eq!(hub.publish("room", &message(1)), 0);
+
+    let frames = frames(peer).await;
+    assert_eq!(frames.len(), 2);
+    assert_eq!(text(&frames[0]), "message 0");
+    assert_eq!(frames[1].close_code(), Some(1008));
+    drop(member);
+}
+
+#[tokio::test]
+async fn publish_encodes_once_for_the_whole_room() {
+    let hub = Hub::new();
+    let members: Vec<_> = (0..3)
+        .map(|_| add(&hub, SlowConsumer::Drop, 1 << 16))
+        .collect();
+    for (member, _) in &members {
+        member.join("room");
+    }
+    let (outsider, outsider_peer) = add(&hub, SlowConsumer::Drop, 1 << 16);
+    outsider.join("elsewhere");
+
+    assert_eq!(hub.publish("room", &Message::Binary(vec![9; 300])), 3);
+    assert_eq!(hub.publish("nobody here", &message(0)), 0);
+    drop(outsider);
+    assert!(frames(outsider_peer).await.is_empty());
+    for (member, peer) in members {
+        drop(member);
+        let frames = frames(peer).await;
+        assert_eq!(frames.len(), 1);
+        //the same unmasked frame for everyone
+        assert_eq!(frames[0].as_bytes()[..4], [0x82, 126, 1, 44]);
+        assert_eq!(frames[0].unmasked_data(), &[9; 300][..]);
+    }
+}
+
+#[tokio::test]
+async fn dropping_a_member_leaves_its_rooms_and_ends_its_connection() {
+    let hub = Hub::new();
+    let (member, peer) = add(&hub, SlowConsumer::Drop, 1 << 16);
+    let (stays, _stays_peer) = add(&hub, SlowConsumer::Drop, 1 << 16);
+    member.join("a");
+    member.join("b");
+    stays.join("a");
+    member.leave("b");
+    assert_eq!((hub.members("a"), hub.members("b")), (2, 0));
+    member.join("b");
+    assert!(member.send(&message(0)));
+
+    drop(member);
+    assert_eq!((hub.members("a"), hub.members("b")), (1, 0));
+    //what was queued still goes out, then the connection ends
+    let frames = frames(peer).await;
+    assert_eq!(frames.len(), 1);
+    assert_eq!(text(&frames[0]), "message 0");
+    assert_eq!(hub.publish("a", &message(1)), 1);
+}
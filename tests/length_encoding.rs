@@ -0,0 +1,74 @@
+// payload length vectors around each boundary of the RFC 6455 5.2 encoding:
+// 7 bit lengths up to 125, 126 + u16 up to 65535, 127 + u64 above that.
+use yaws::{Extensions, Frame, FrameError, LengthEncoding, Opcode, Role, WsParsingError};
+
+//(second header byte and extended length bytes, decoded length, minimally encoded)
+type Vector = (&'static [u8], u64, bool);
+
+const VECTORS: &[Vector] = &[
+    (&[0], 0, true),
+    (&[125], 125, true),
+    (&[126, 0x00, 0x7E], 126, true),
+    (&[126, 0xFF, 0xFF], 65535, true),
+    (&[127, 0, 0, 0, 0, 0, 0x01, 0x00, 0x00], 65536, true),
+    (&[126, 0x00, 0x00], 0, false),
+    (&[126, 0x00, 0x7D], 125, false),
+    (&[127, 0, 0, 0, 0, 0, 0, 0x00, 0x7E], 126, false),
+    (&[127, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF], 65535, false),
+];
+
+// an unmasked, final binary frame with the given length encoding.
+fn frame_bytes(&(header, len, _): &Vector) -> Vec<u8> {
+    let mut bytes = vec![0x82];
+    bytes.extend_from_slice(header);
+    bytes.resize(bytes.len() + len as usize, 0xAA);
+    bytes
+}
+
+#[test]
+fn parses_every_boundary() {
+    for vector @ &(header, len, minimal) in VECTORS {
+        let bytes = frame_bytes(vector);
+        let (frame, remainder) = Frame::parse_slice(&bytes).unwrap();
+        assert!(remainder.is_empty());
+        assert_eq!(frame.payload_len(), len);
+        assert_eq!(frame.header_size(), 1 + header.len());
+        assert_eq!(frame.has_minimal_length(), minimal, "{:?}", header);
+    }
+}
+
+#[test]
+fn strict_rejects_non_minimal_lengths() {
+    for vector @ &(_, _, minimal) in VECTORS {
+        let bytes = frame_bytes(vector);
+        let (frame, _) = Frame::parse_slice(&bytes).unwrap();
+        let strict = frame.validate_with(Role::Client, Extensions::none(), LengthEncoding::Strict);
+        let lenient =
+            frame.validate_with(Role::Client, Extensions::none(), LengthEncoding::Lenient);
+        if minimal {
+            assert_eq!(strict, Ok(()));
+        } else {
+            assert_eq!(strict, Err(FrameError::NonMinimalLength));
+        }
+        assert_eq!(lenient, Ok(()));
+        assert_eq!(frame.is_valid(), minimal);
+    }
+}
+
+#[test]
+fn encodes_minimal_lengths() {
+    for &(expected, len, _) in VECTORS.iter().filter(|vector| vector.2) {
+        let (header, header_len) = Frame::encode_header(Opcode::Binary, true, len as usize, None);
+        assert_eq!(header[0], 0x82);
+        assert_eq!(&header[1..header_len], expected);
+    }
+}
+
+#[test]
+fn rejects_length_with_msb_set() {
+    let bytes = [0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 0];
+    assert!(matches!(
+        Frame::parse_slice(&bytes),
+        Err(WsParsingError::LengthOverflow)
+    ));
+}
@@ -0,0 +1,48 @@
+// the word and SIMD masking routines against the byte at a time reference, starting at
+// every alignment and with every tail length the wider loads leave behind.
+use yaws::mask::{apply_mask, apply_mask_scalar, apply_mask_words};
+
+const MASKS: &[u32] = &[0, 0xFFFF_FFFF, 0x0102_0304, 0xDEAD_BEEF];
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+fn matches_scalar(masking: fn(&mut [u8], u32)) {
+    for &mask in MASKS {
+        for offset in 0..16 {
+            for len in 0..300 {
+                //the extra bytes around the payload shift where it starts and catch overruns
+                let mut expected = payload(offset + len + 16);
+                let mut actual = expected.clone();
+                apply_mask_scalar(&mut expected[offset..offset + len], mask);
+                masking(&mut actual[offset..offset + len], mask);
+                assert_eq!(
+                    actual, expected,
+                    "mask {:08x}, offset {}, len {}",
+                    mask, offset, len
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn words_match_scalar() {
+    matches_scalar(apply_mask_words);
+}
+
+#[test]
+fn dispatched_matches_scalar() {
+    matches_scalar(apply_mask);
+}
+
+#[test]
+fn masking_twice_restores_the_payload() {
+    let original = payload(1000);
+    let mut data = original.clone();
+    apply_mask(&mut data[3..], 0x0102_0304);
+    assert_ne!(data, original);
+    apply_mask(&mut data[3..], 0x0102_0304);
+    assert_eq!(data, original);
+}
@@ -0,0 +1,72 @@
+// connections over a server's limits are told so right away, and don't get to hold on
+// to the server for long, tls handshake included.
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ClientConfig;
+use yaws::{
+    load_certs, Client, ConnectOptions, Limits, Router, SecureServer, Server, TlsServerConfig,
+};
+
+fn nobody() -> Limits {
+    Limits::default().with_max_connections(0)
+}
+
+#[tokio::test]
+async fn answers_before_the_request_arrives() {
+    let router = Router::new().route("/", |_, _| async {});
+    let server = Server::bind("127.0.0.1:0", router)
+        .await
+        .unwrap()
+        .with_limits(nobody());
+    let addr = server.local_addr().unwrap();
+    let admission = server.admission();
+    tokio::spawn(server.run());
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let mut response = vec![0; 512];
+    let n = tokio::time::timeout(Duration::from_millis(500), stream.read(&mut response))
+        .await
+        .expect("no response without a request")
+        .unwrap();
+    let response = String::from_utf8_lossy(&response[..n]);
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+    assert!(response.contains("retry-after: 1\r\n"), "{}", response);
+    assert_eq!(admission.stats().rejected_busy, 1);
+}
+
+#[tokio::test]
+async fn refused_tls_handshakes_get_the_refusal_deadline() {
+    let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/");
+    let config = TlsServerConfig::new()
+        .with_cert(format!("{}ip.pem", certs), format!("{}ip.key", certs))
+        .unwrap()
+        .build();
+    let router = Router::new().route("/", |_, _| async {});
+    let server = SecureServer::bind("127.0.0.1:0", config, router)
+        .await
+        .unwrap()
+        .with_limits(nobody());
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    //never starts the handshake, the server's 10s handshake timeout doesn't apply
+    let started = Instant::now();
+    let mut stalled = TcpStream::connect(addr).await.unwrap();
+    let read = tokio::time::timeout(Duration::from_secs(3), stalled.read(&mut [0; 64])).await;
+    assert_eq!(read.expect("still open after 3s").unwrap(), 0);
+    assert!(started.elapsed() >= Duration::from_millis(900));
+
+    //one that does the handshake is told why
+    let mut tls_config = ClientConfig::new();
+    for cert in load_certs(format!("{}ca.pem", certs)).unwrap() {
+        tls_config.root_store.add(&cert).unwrap();
+    }
+    let options = ConnectOptions::new().with_tls_config(Arc::new(tls_config));
+    let e = Client::connect_secure_with(format!("wss://{}/", addr), &options)
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::ConnectionRefused);
+    assert!(e.to_string().contains("503"), "{}", e);
+}
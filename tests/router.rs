@@ -0,0 +1,159 @@
+// the server side of the handshake: the accept key, which route a path goes to, and the
+// 400 and 426 answers to requests that aren't valid upgrades.
+use futures_util::StreamExt;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use yaws::{accept_key, Message, Router};
+
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+fn router() -> Arc<Router<DuplexStream>> {
+    let router = Router::new()
+        .route(
+            "/rooms/{room}/users/{user}",
+            |mut client, request| async move {
+                let params = format!(
+                    "{} {}",
+                    request.param("room").unwrap(),
+                    request.param("user").unwrap()
+                );
+                let _ = client.send_str(params).await;
+            },
+        )
+        .route("/feed/", |mut client, _| async move {
+            let _ = client.send_str("feed").await;
+        })
+        .route("/", |mut client, _| async move {
+            let _ = client.send_str("root").await;
+        });
+    Arc::new(router)
+}
+
+fn upgrade(path: &str) -> String {
+    format!(
+        "GET {} HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n\r\n",
+        path, KEY
+    )
+}
+
+// sends `request` and returns the response head, with the stream left after it.
+async fn send(request: &str) -> (String, DuplexStream) {
+    let (stream, mut peer) = tokio::io::duplex(1 << 16);
+    let router = router();
+    tokio::spawn(async move { router.serve(stream).await });
+    peer.write_all(request.as_bytes()).await.unwrap();
+    let mut head = vec![];
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        if peer.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    (String::from_utf8(head).unwrap(), peer)
+}
+
+async fn status(request: &str) -> u16 {
+    let (head, _) = send(request).await;
+    head[9..12].parse().unwrap()
+}
+
+// the text the handler the request was routed to sent, None if it wasn't upgraded.
+async fn routed_to(path: &str) -> Option<String> {
+    let (head, peer) = send(&upgrade(path)).await;
+    if !head.starts_with("HTTP/1.1 101") {
+        return None;
+    }
+    let mut client = yaws::Client::from_upgraded(peer, yaws::Role::Client);
+    match client.next().await.unwrap().unwrap() {
+        Message::Text(text) => Some(text),
+        other => panic!("expected text, got {:?}", other),
+    }
+}
+
+#[test]
+fn accept_key_matches_the_rfc_example() {
+    //RFC 6455 1.3
+    assert_eq!(accept_key(KEY), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[tokio::test]
+async fn answers_with_the_accept_key() {
+    let (head, _) = send(&upgrade("/")).await;
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+        "{}",
+        head
+    );
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+}
+
+#[tokio::test]
+async fn routes_by_path_with_params() {
+    assert_eq!(
+        routed_to("/rooms/lobby/users/7").await.as_deref(),
+        Some("lobby 7")
+    );
+    //the query isn't part of the path
+    assert_eq!(
+        routed_to("/rooms/a/users/b?x=1").await.as_deref(),
+        Some("a b")
+    );
+    assert_eq!(routed_to("/").await.as_deref(), Some("root"));
+    //params can't be empty, and the number of segments has to match
+    assert_eq!(routed_to("/rooms//users/7").await, None);
+    assert_eq!(routed_to("/rooms/lobby/users").await, None);
+    assert_eq!(routed_to("/rooms/lobby/users/7/8").await, None);
+    assert_eq!(status(&upgrade("/nowhere")).await, 404);
+}
+
+#[tokio::test]
+async fn trailing_slashes_have_to_match() {
+    assert_eq!(routed_to("/feed/").await.as_deref(), Some("feed"));
+    assert_eq!(routed_to("/feed").await, None);
+    assert_eq!(routed_to("/rooms/lobby/users/7/").await, None);
+    assert_eq!(status(&upgrade("/rooms/lobby/users/7/")).await, 404);
+}
+
+#[tokio::test]
+async fn header_tokens_are_case_insensitive_lists() {
+    let request = upgrade("/")
+        .replace("Upgrade: websocket", "Upgrade: WebSocket")
+        .replace("Connection: Upgrade", "Connection: keep-alive, upgrade");
+    assert_eq!(status(&request).await, 101);
+}
+
+#[tokio::test]
+async fn refuses_requests_that_are_not_upgrades() {
+    let request = upgrade("/");
+    let bad_requests = [
+        request.replace("Upgrade: websocket\r\n", ""),
+        request.replace("Upgrade: websocket", "Upgrade: h2c"),
+        request.replace("Connection: Upgrade", "Connection: keep-alive"),
+        request.replace(&format!("Sec-WebSocket-Key: {}\r\n", KEY), ""),
+        //a key has to be 16 bytes of base64
+        request.replace(KEY, "c2hvcnQ="),
+        request.replace(KEY, "not base64 at all!"),
+        request.replace("GET", "POST"),
+        request.replace("HTTP/1.1", "HTTP/1.0"),
+        "GET /\r\n\r\n".to_owned(),
+    ];
+    for request in &bad_requests {
+        assert_eq!(status(request).await, 400, "{:?}", request);
+    }
+}
+
+#[tokio::test]
+async fn asks_for_version_13() {
+    for version in ["8", "14"] {
+        let request = upgrade("/").replace(
+            "Sec-WebSocket-Version: 13",
+            &format!("Sec-WebSocket-Version: {}", version),
+        );
+        let (head, _) = send(&request).await;
+        assert!(head.starts_with("HTTP/1.1 426"), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Version: 13\r\n"), "{}", head);
+    }
+    let missing = upgrade("/").replace("Sec-WebSocket-Version: 13\r\n", "");
+    assert_eq!(status(&missing).await, 426);
+}
@@ -0,0 +1,43 @@
+// frames and messages past the Client's size limits fail the read with 1009, before
+// the reader makes room for them.
+use futures_util::StreamExt;
+use tokio::io::AsyncWriteExt;
+use yaws::{Client, Message, Role, TooBig};
+
+fn too_big(e: &std::io::Error) -> TooBig {
+    *e.get_ref().unwrap().downcast_ref::<TooBig>().unwrap()
+}
+
+#[tokio::test]
+async fn rejects_a_huge_frame_from_its_header() {
+    let (stream, mut peer) = tokio::io::duplex(1 << 16);
+    let mut client = Client::from_upgraded(stream, Role::Client);
+    //a binary frame claiming 2^44 bytes, none of which ever arrive
+    let mut header = vec![0x82, 127];
+    header.extend_from_slice(&(1u64 << 44).to_be_bytes());
+    peer.write_all(&header).await.unwrap();
+    let e = client.next().await.unwrap().unwrap_err();
+    assert_eq!(too_big(&e), TooBig::Frame(1 << 44, 16 << 20));
+    assert_eq!(too_big(&e).close_code(), 1009);
+}
+
+#[tokio::test]
+async fn limits_fragmented_messages() {
+    let fragments = [0x02, 6, 1, 2, 3, 4, 5, 6, 0x80, 6, 1, 2, 3, 4, 5, 6];
+    let (stream, mut peer) = tokio::io::duplex(1 << 16);
+    let mut client = Client::from_upgraded(stream, Role::Client);
+    client.set_max_message_size(10);
+    peer.write_all(&fragments).await.unwrap();
+    let e = client.next().await.unwrap().unwrap_err();
+    assert_eq!(too_big(&e), TooBig::Message(12, 10));
+
+    let (stream, mut peer) = tokio::io::duplex(1 << 16);
+    let mut client = Client::from_upgraded(stream, Role::Client);
+    client.set_max_message_size(12);
+    peer.write_all(&fragments).await.unwrap();
+    let message = client.next().await.unwrap().unwrap();
+    assert_eq!(
+        message,
+        Message::Binary(vec![1, 2, 3, 4, 5, 6, 1, 2, 3, 4, 5, 6])
+    );
+}
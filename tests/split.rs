@@ -0,0 +1,71 @@
+// the halves of a split client: replies from the reader never land inside one of the
+// writer's frames, and only halves of the same split reunite.
+use bytes::BytesMut;
+use futures_util::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Decoder;
+use yaws::{Client, Frame, Opcode, Role, WsCodec};
+
+#[tokio::test]
+async fn replies_go_out_between_the_writers_frames() {
+    //small enough that the writer stalls partway through its first frame
+    let (stream, mut peer) = tokio::io::duplex(64);
+    let (mut reader, mut writer) = Client::from_upgraded(stream, Role::Server).split();
+    let writing = tokio::spawn(async move {
+        for fill in 1..=3u8 {
+            writer.send_binary(&[fill; 20_000]).await.unwrap();
+        }
+        writer
+    });
+    let reading = tokio::spawn(async move { while let Some(Ok(_)) = reader.next().await {} });
+
+    peer.write_all(Frame::new_ping(Some(b"are you there"), Some(0xA1B2_C3D4)).as_bytes())
+        .await
+        .unwrap();
+    let mut codec = WsCodec::new(Role::Client);
+    let mut received = BytesMut::new();
+    let mut frames = vec![];
+    while frames.len() < 4 {
+        //a few bytes at a time, so the reader gets its pong queued mid-frame
+        let mut chunk = [0; 7];
+        let n = peer.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "the connection ended after {:?}", frames);
+        received.extend_from_slice(&chunk[..n]);
+        while let Some(frame) = codec.decode(&mut received).unwrap() {
+            frames.push(frame);
+        }
+    }
+    writing.await.unwrap();
+
+    let binaries: Vec<_> = frames
+        .iter()
+        .filter(|frame| frame.opcode() == Opcode::Binary)
+        .collect();
+    assert_eq!(binaries.len(), 3);
+    for (frame, fill) in binaries.iter().zip(1..) {
+        assert_eq!(frame.unmasked_data(), &[fill; 20_000][..]);
+    }
+    let pong = frames.iter().position(|f| f.opcode() == Opcode::Pong);
+    let pong = pong.expect("the ping was never answered");
+    //the ping arrived while the first frame was stuck in the pipe, so the pong had to wait
+    assert!(pong > 0);
+    let pong = &frames[pong];
+    assert_eq!(pong.unmasked_data(), b"are you there");
+    assert!(received.is_empty());
+    drop(peer);
+    reading.await.unwrap();
+}
+
+#[tokio::test]
+async fn reunite_rejects_halves_of_different_connections() {
+    let (a, _peer_a) = tokio::io::duplex(64);
+    let (b, _peer_b) = tokio::io::duplex(64);
+    let (reader_a, writer_a) = Client::from_upgraded(a, Role::Client).split();
+    let (reader_b, writer_b) = Client::from_upgraded(b, Role::Client).split();
+
+    let mismatched = reader_a.reunite(writer_b).unwrap_err();
+    let yaws::ReuniteError(reader_a, writer_b) = *mismatched;
+    //the halves come back unharmed and still pair up with their own
+    assert!(reader_a.reunite(writer_a).is_ok());
+    assert!(reader_b.reunite(writer_b).is_ok());
+}
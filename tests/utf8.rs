@@ -0,0 +1,93 @@
+// the incremental validator against std's, for every way of splitting a message in
+// two or three fragments.
+use yaws::{InvalidUtf8, Utf8Validator};
+
+fn validate(fragments: &[&[u8]]) -> Result<(), InvalidUtf8> {
+    let mut validator = Utf8Validator::new();
+    for fragment in fragments {
+        validator.feed(fragment)?;
+    }
+    validator.finish()
+}
+
+// every split of `bytes` into two and three fragments gives the same answer as std.
+fn agrees_with_std(bytes: &[u8]) {
+    let expected = std::str::from_utf8(bytes).is_ok();
+    for i in 0..=bytes.len() {
+        let (a, rest) = bytes.split_at(i);
+        assert_eq!(
+            validate(&[a, rest]).is_ok(),
+            expected,
+            "{:x?} at {}",
+            bytes,
+            i
+        );
+        for j in 0..=rest.len() {
+            let (b, c) = rest.split_at(j);
+            let split = validate(&[a, b, c]).is_ok();
+            assert_eq!(split, expected, "{:x?} at {} and {}", bytes, i, i + j);
+        }
+    }
+}
+
+#[test]
+fn code_points_split_at_every_offset() {
+    agrees_with_std("a\u{7F}\u{80}\u{7FF}\u{800}\u{FFFF}\u{10000}\u{10FFFF}z".as_bytes());
+    agrees_with_std("κόσμε 🦀 ünïcödé".as_bytes());
+}
+
+#[test]
+fn overlong_encodings() {
+    //'/' encoded in 2, 3 and 4 bytes, and the largest overlongs of each width
+    for bytes in [
+        &[0xC0, 0xAF][..],
+        &[0xE0, 0x80, 0xAF],
+        &[0xF0, 0x80, 0x80, 0xAF],
+        &[0xC1, 0xBF],
+        &[0xE0, 0x9F, 0xBF],
+        &[0xF0, 0x8F, 0xBF, 0xBF],
+    ] {
+        assert_eq!(validate(&[bytes]), Err(InvalidUtf8));
+        agrees_with_std(&[b"ab", bytes, b"cd"].concat());
+    }
+}
+
+#[test]
+fn surrogates() {
+    for bytes in [&[0xED, 0xA0, 0x80][..], &[0xED, 0xBF, 0xBF]] {
+        assert_eq!(validate(&[bytes]), Err(InvalidUtf8));
+        agrees_with_std(&[b"x", bytes].concat());
+    }
+}
+
+#[test]
+fn past_the_last_code_point() {
+    //U+110000 and a 0xF5 lead byte, which could only start something larger still
+    for bytes in [&[0xF4, 0x90, 0x80, 0x80][..], &[0xF5, 0x80, 0x80, 0x80]] {
+        assert_eq!(validate(&[bytes]), Err(InvalidUtf8));
+        agrees_with_std(&[b"x", bytes, b"y"].concat());
+    }
+}
+
+#[test]
+fn fails_as_soon_as_a_prefix_is_invalid() {
+    let mut validator = Utf8Validator::new();
+    validator.feed(&[0xE2]).unwrap();
+    //0x28 can't continue a code point, no need to see the rest
+    assert_eq!(validator.feed(&[0x28]), Err(InvalidUtf8));
+}
+
+#[test]
+fn truncated_at_the_end_of_the_final_fragment() {
+    let euro = "€".as_bytes();
+    for len in 1..euro.len() {
+        assert_eq!(validate(&[b"price: ", &euro[..len]]), Err(InvalidUtf8));
+        assert_eq!(validate(&[&euro[..len], &[]]), Err(InvalidUtf8));
+    }
+    //finish resets it for the next message
+    let mut validator = Utf8Validator::new();
+    validator.feed(&euro[..1]).unwrap();
+    assert!(validator.finish().is_err());
+    validator.feed(b"ok").unwrap();
+    assert!(validator.finish().is_ok());
+}